@@ -13,7 +13,7 @@ fn git_describe_value() -> String {
 
     // Fallback to using git command
     Command::new("git")
-        .args(&["describe", "--tags", "--always"])
+        .args(["describe", "--tags", "--always"])
         .output()
         .ok()
         .and_then(|output| {
@@ -1,10 +1,14 @@
-use eyre::Result;
+use eyre::{eyre, Result};
 use itertools::Itertools;
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::path::Path;
 use std::str::FromStr;
 use void::Void;
 
+/// An escaped dollar in an alias value is emitted as a literal `$` and never treated as a parameter
+const ESCAPED_DOLLAR: &str = "\\$";
+
 const fn default_true() -> bool {
     true
 }
@@ -13,11 +17,56 @@ const fn default_false() -> bool {
     false
 }
 
-#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+const fn is_true(value: &bool) -> bool {
+    *value
+}
+
+const fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// Where an alias applies; an alias whose condition fails is treated as absent
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct When {
+    /// A file or directory that must exist in the current working directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd_contains: Option<String>,
+}
+
+impl When {
+    #[must_use]
+    pub fn holds(&self, cwd: Option<&Path>) -> bool {
+        match &self.cwd_contains {
+            Some(marker) => cwd.is_some_and(|cwd| cwd.join(marker).exists()),
+            None => true,
+        }
+    }
+}
+
+/// Accept a value written as a string, or as a list of strings joined with single spaces
+fn deserialize_value<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(value) => value,
+        StringOrList::List(parts) => parts.join(" "),
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Alias {
     #[serde(skip_deserializing)]
     pub name: String,
 
+    #[serde(deserialize_with = "deserialize_value")]
     pub value: String,
 
     #[serde(default = "default_true")]
@@ -25,6 +74,42 @@ pub struct Alias {
 
     #[serde(default = "default_false")]
     pub global: bool,
+
+    #[serde(default = "default_false", skip_serializing_if = "is_false")]
+    pub regex: bool,
+
+    /// Expand only after the command, never as the command itself
+    #[serde(default = "default_false", skip_serializing_if = "is_false")]
+    pub global_only: bool,
+
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    pub enabled: bool,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<When>,
+}
+
+impl Default for Alias {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            value: String::new(),
+            space: default_true(),
+            global: default_false(),
+            regex: default_false(),
+            global_only: default_false(),
+            description: String::new(),
+            tags: Vec::new(),
+            enabled: default_true(),
+            when: None,
+        }
+    }
 }
 
 impl Alias {
@@ -36,7 +121,7 @@ impl Alias {
     pub fn positionals(&self) -> Result<Vec<String>> {
         let re = Regex::new(r"(\$[1-9])")?;
         let items = re
-            .find_iter(&self.value)
+            .find_iter(&self.unescaped_value())
             .filter_map(|m| m.as_str().parse().ok())
             .unique()
             .sorted()
@@ -44,6 +129,20 @@ impl Alias {
         Ok(items)
     }
 
+    /// Return the numeric indices of the positional arguments, sorted
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if there was a problem in processing the positional arguments.
+    pub fn positional_indices(&self) -> Result<Vec<u32>> {
+        let items = self
+            .positionals()?
+            .iter()
+            .filter_map(|positional| positional.trim_start_matches('$').parse().ok())
+            .collect();
+        Ok(items)
+    }
+
     /// Return the keyword arguments
     ///
     /// # Errors
@@ -52,7 +151,7 @@ impl Alias {
     pub fn keywords(&self) -> Result<Vec<String>> {
         let re = Regex::new(r"(\$[A-z]+)")?;
         let items = re
-            .find_iter(&self.value)
+            .find_iter(&self.unescaped_value())
             .filter_map(|m| m.as_str().parse().ok())
             .unique()
             .sorted()
@@ -60,9 +159,84 @@ impl Alias {
         Ok(items)
     }
 
+    /// Return the compiled name pattern for a regex alias
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the alias name is not a valid regular expression.
+    pub fn pattern(&self) -> Result<Option<Regex>> {
+        if !self.regex {
+            return Ok(None);
+        }
+        let re = Regex::new(&self.name).map_err(|err| eyre!("alias '{}' is not a valid regex: {}", self.name, err))?;
+        Ok(Some(re))
+    }
+
+    /// Match `arg` against `re`, this alias's compiled pattern, returning the alias with capture groups
+    /// substituted for `$1`..`$9`. A `$N` past the last group is left for positional arguments.
+    #[must_use]
+    pub fn captures(&self, re: &Regex, arg: &str) -> Option<Self> {
+        let caps = re.captures(arg)?;
+        let mut pieces: Vec<String> = self.value.split(ESCAPED_DOLLAR).map(String::from).collect();
+        for index in (1..caps.len().min(10)).rev() {
+            let capture = caps.get(index).map_or("", |m| m.as_str());
+            for piece in &mut pieces {
+                *piece = piece.replace(&format!("${index}"), capture);
+            }
+        }
+        let value = pieces.join(ESCAPED_DOLLAR);
+        Some(Self {
+            name: arg.to_string(),
+            value,
+            regex: false,
+            ..self.clone()
+        })
+    }
+
+    /// Whether the alias may expand after the command
+    #[must_use]
+    pub const fn is_global(&self) -> bool {
+        self.global || self.global_only
+    }
+
+    /// The quote left open at the end of the value, with its byte offset, following shell quoting rules
+    #[must_use]
+    pub fn open_quote(&self) -> Option<(char, usize)> {
+        let mut open: Option<(char, usize)> = None;
+        let mut chars = self.value.char_indices();
+        while let Some((index, c)) = chars.next() {
+            match open {
+                Some(('\'', _)) if c == '\'' => open = None,
+                Some(('\'', _)) => {}
+                _ if c == '\\' => {
+                    chars.next();
+                }
+                Some((q, _)) if q == c => open = None,
+                None if c == '"' || c == '\'' => open = Some((c, index)),
+                _ => {}
+            }
+        }
+        open
+    }
+
+    /// The value with escaped dollars turned into the literal `$` they stand for, as `replace` emits them
+    #[must_use]
+    pub fn literal_value(&self) -> String {
+        self.value.replace(ESCAPED_DOLLAR, "$")
+    }
+
+    /// The value with escaped dollars removed, for detecting parameters
+    fn unescaped_value(&self) -> String {
+        self.value.replace(ESCAPED_DOLLAR, "")
+    }
+
+    /// Whether the alias consumes the arguments that follow it, via `$@`, `$*` or a `$N` positional
     #[must_use]
     pub fn is_variadic(&self) -> bool {
-        self.value.contains("$@")
+        self.unescaped_value()
+            .as_bytes()
+            .windows(2)
+            .any(|pair| pair[0] == b'$' && matches!(pair[1], b'@' | b'*' | b'1'..=b'9'))
     }
 
     /// Replace the remainder of the arguments.
@@ -73,24 +247,31 @@ impl Alias {
     /// - If there was a problem retrieving positional parameters.
     /// - If the alias is not variadic and the number of positional parameters doesn't match the number of remaining arguments.
     pub fn replace(&self, remainders: &mut Vec<String>) -> Result<(String, usize)> {
-        let mut result = self.value.clone();
+        // Substitute within the pieces between escaped dollars, which are joined back as literal `$`
+        let mut pieces: Vec<String> = self.value.split(ESCAPED_DOLLAR).map(String::from).collect();
         let mut count = 0;
         let positionals = self.positionals()?;
-        if positionals.len() > 0 {
+        if !positionals.is_empty() {
             if positionals.len() == remainders.len() {
                 for positional in &positionals {
-                    result = result.replace(positional, &remainders.swap_remove(0));
+                    let arg = remainders.remove(0);
+                    for piece in &mut pieces {
+                        *piece = piece.replace(positional, &arg);
+                    }
                 }
                 count = positionals.len();
             } else {
-                result = self.name.clone();
+                return Ok((self.name.clone(), 0));
             }
         } else if self.is_variadic() {
-            result = result.replace("$@", &remainders.join(" "));
+            let args = remainders.join(" ");
+            for piece in &mut pieces {
+                *piece = piece.replace("$@", &args).replace("$*", &args);
+            }
             count = remainders.len();
             remainders.drain(0..remainders.len());
         }
-        Ok((result, count))
+        Ok((pieces.join("$"), count))
     }
 }
 
@@ -103,6 +284,7 @@ impl FromStr for Alias {
             value: s.to_owned(),
             space: true,
             global: false,
+            ..Default::default()
         })
     }
 }
@@ -118,12 +300,37 @@ mod tests {
             value: "echo $1 $2".to_string(),
             space: true,
             global: false,
+            ..Default::default()
         };
 
         assert_eq!(alias.positionals()?, vec!["$1", "$2"]);
         Ok(())
     }
 
+    #[test]
+    fn test_positional_indices() -> Result<()> {
+        let contiguous = Alias {
+            name: "contiguous".to_string(),
+            value: "echo $2 $1 $2".to_string(),
+            ..Default::default()
+        };
+        let gapped = Alias {
+            name: "gapped".to_string(),
+            value: "echo $1 $3".to_string(),
+            ..Default::default()
+        };
+        let variadic = Alias {
+            name: "variadic".to_string(),
+            value: "echo $@".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(contiguous.positional_indices()?, vec![1, 2]);
+        assert_eq!(gapped.positional_indices()?, vec![1, 3]);
+        assert_eq!(variadic.positional_indices()?, Vec::<u32>::new());
+        Ok(())
+    }
+
     #[test]
     fn test_keywords() -> Result<()> {
         let alias = Alias {
@@ -131,6 +338,7 @@ mod tests {
             value: "echo $name $location".to_string(),
             space: true,
             global: false,
+            ..Default::default()
         };
 
         assert_eq!(alias.keywords()?, vec!["$location", "$name"]);
@@ -144,9 +352,57 @@ mod tests {
             value: "echo $@".to_string(),
             space: true,
             global: false,
+            ..Default::default()
         };
 
         assert!(alias.is_variadic());
+
+        let star = Alias {
+            value: "echo $*".to_string(),
+            ..Default::default()
+        };
+        assert!(star.is_variadic());
+
+        let positional = Alias {
+            value: "git commit -m $1".to_string(),
+            ..Default::default()
+        };
+        assert!(positional.is_variadic());
+
+        let plain = Alias {
+            value: "git status".to_string(),
+            ..Default::default()
+        };
+        assert!(!plain.is_variadic());
+    }
+
+    #[test]
+    fn test_replace_star() -> Result<()> {
+        let alias = Alias {
+            name: "e".to_string(),
+            value: "echo $*".to_string(),
+            ..Default::default()
+        };
+        let mut remainders = vec!["Hello".to_string(), "World".to_string()];
+        assert_eq!(alias.replace(&mut remainders)?, ("echo Hello World".to_string(), 2));
+        assert!(remainders.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_quote() {
+        let open = |value: &str| {
+            Alias {
+                value: value.to_string(),
+                ..Default::default()
+            }
+            .open_quote()
+        };
+        assert_eq!(open(r#"git commit -m "wip""#), None);
+        assert_eq!(open(r#"echo 'it"s' "it's" \""#), None);
+        assert_eq!(open(r#"git commit -m ""#), Some(('"', 14)));
+        assert_eq!(open(r#"echo 'oops"#), Some(('\'', 5)));
+        assert_eq!(open(r#"echo "a \" b"#), Some(('"', 5)));
     }
 
     #[test]
@@ -156,6 +412,7 @@ mod tests {
             value: "echo $1 $2".to_string(),
             space: true,
             global: false,
+            ..Default::default()
         };
 
         let mut remainders = vec!["Hello".to_string(), "World".to_string()];
@@ -167,6 +424,7 @@ mod tests {
             value: "echo $@".to_string(),
             space: true,
             global: false,
+            ..Default::default()
         };
 
         let mut remainders_variadic = vec!["Hello".to_string(), "from".to_string(), "Rust".to_string()];
@@ -197,6 +455,7 @@ mod tests {
             value: "echo Hello World".to_string(),
             space: true,
             global: false,
+            ..Default::default()
         };
 
         assert_eq!(alias.positionals()?, Vec::<String>::new());
@@ -211,6 +470,7 @@ mod tests {
             value: "echo $@".to_string(),
             space: true,
             global: false,
+            ..Default::default()
         };
 
         let mut remainders = vec!["Hello".to_string(), "World".to_string()];
@@ -219,6 +479,89 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_captures() -> Result<()> {
+        let alias = Alias {
+            name: "^deploy-(.+)$".to_string(),
+            value: "kubectl apply -f $1.yaml".to_string(),
+            space: true,
+            global: false,
+            regex: true,
+            ..Default::default()
+        };
+
+        let re = alias.pattern()?.expect("a regex alias has a pattern");
+        let matched = alias.captures(&re, "deploy-web").expect("should match");
+        assert_eq!(matched.name, "deploy-web");
+        assert_eq!(matched.value, "kubectl apply -f web.yaml");
+        assert!(!matched.regex);
+        assert_eq!(alias.captures(&re, "undeploy"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pattern_invalid_regex() {
+        let alias = Alias {
+            name: "deploy-(".to_string(),
+            value: "echo".to_string(),
+            regex: true,
+            ..Default::default()
+        };
+
+        assert!(alias.pattern().is_err());
+    }
+
+    #[test]
+    fn test_replace_keeps_argument_order() -> Result<()> {
+        let alias = Alias {
+            name: "alias".to_string(),
+            value: "echo $1 $2 $3".to_string(),
+            ..Default::default()
+        };
+
+        let mut remainders = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        assert_eq!(alias.replace(&mut remainders)?, ("echo a b c".to_string(), 3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_escaped_dollar_is_literal() -> Result<()> {
+        let alias = Alias {
+            name: "col".to_string(),
+            value: r"awk '{print \$1}'".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(alias.positionals()?, Vec::<String>::new());
+        let mut remainders = vec!["file.txt".to_string()];
+        assert_eq!(alias.replace(&mut remainders)?, ("awk '{print $1}'".to_string(), 0));
+        assert_eq!(remainders, vec!["file.txt".to_string()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_escaped_dollar_with_positional() -> Result<()> {
+        let alias = Alias {
+            name: "col".to_string(),
+            value: r"awk '{print \$1}' $1".to_string(),
+            ..Default::default()
+        };
+
+        let mut remainders = vec!["file.txt".to_string()];
+        assert_eq!(
+            alias.replace(&mut remainders)?,
+            ("awk '{print $1}' file.txt".to_string(), 1)
+        );
+
+        let variadic = Alias {
+            name: "price".to_string(),
+            value: r"echo \$@ costs \$5".to_string(),
+            ..Default::default()
+        };
+        assert!(!variadic.is_variadic());
+        Ok(())
+    }
+
     #[test]
     fn test_replace_mismatch_remainders() -> Result<()> {
         let alias = Alias {
@@ -226,6 +569,7 @@ mod tests {
             value: "echo $1 $2 $3".to_string(),
             space: true,
             global: false,
+            ..Default::default()
         };
 
         let mut remainders = vec!["Hello".to_string(), "World".to_string()];
@@ -2,7 +2,7 @@ use eyre::{Error, Result, WrapErr};
 use std::fs;
 use std::path::PathBuf;
 
-use super::spec::Spec;
+use super::spec::{migrate_spec, Spec};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Loader {}
@@ -17,10 +17,27 @@ impl Loader {
     ///
     /// # Errors
     ///
-    /// Will return `Err` if `filename` does not exist, or the user does not have permission to read it.
+    /// Will return `Err` if `filename` does not exist, the user does not have permission to read it,
+    /// an alias name is defined more than once, an alias references an unset environment variable, or a regex alias fails to compile.
     pub fn load(&self, filename: &PathBuf) -> Result<Spec, Error> {
         let content = fs::read_to_string(filename).context(format!("Can't load filename={filename:?}"))?;
-        let spec: Spec = serde_yaml::from_str(&content).context(format!("Can't load content={content:?}"))?;
+        self.load_from_str(&content)
+            .context(format!("Can't parse filename={filename:?}"))
+    }
+
+    /// Load the configuration from YAML text
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `content` is not a valid spec, its version is unsupported, an alias name is defined more than once,
+    /// an alias references an unset environment variable, or a regex alias fails to compile.
+    pub fn load_from_str(&self, content: &str) -> Result<Spec, Error> {
+        let spec: Spec = serde_yaml::from_str(content).context(format!("Can't load content={content:?}"))?;
+        let version = spec.defaults.version;
+        let mut spec = migrate_spec(spec, version)?;
+        spec.interpolate_env()?;
+        spec.compile_patterns()?;
+        spec.expand_lookup_keys();
         Ok(spec)
     }
 }
@@ -73,6 +90,7 @@ lookups:
                     value: "echo Hello World".to_string(),
                     space: true,
                     global: false,
+                    ..Default::default()
                 },
             );
             map
@@ -80,11 +98,30 @@ lookups:
 
         assert_eq!(spec.aliases, expected_aliases);
         assert_eq!(spec.defaults.version, 1);
-        assert_eq!(spec.lookups["region"]["prod|apps"], "us-east-1");
+        assert_eq!(spec.lookups["region"]["prod"], "us-east-1");
+        assert_eq!(spec.lookups["region"]["apps"], "us-east-1");
+        assert_eq!(spec.lookups["region"]["dev"], "us-west-2");
+        assert!(!spec.lookups["region"].contains_key("prod|apps"));
 
         Ok(())
     }
 
+    #[test]
+    fn test_load_from_str() -> Result<(), Error> {
+        let loader = Loader::new();
+        let spec = loader.load_from_str("aliases:\n  gs: git status\n")?;
+        assert_eq!(spec.aliases["gs"].name, "gs");
+        assert_eq!(spec.aliases["gs"].value, "git status");
+
+        let spec = loader.load_from_str("lookups:\n  region:\n    prod|apps: us-east-1\n    dev: us-west-2\n")?;
+        assert_eq!(spec.lookups["region"].len(), 3);
+        assert_eq!(spec.lookups["region"]["apps"], "us-east-1");
+        assert_eq!(spec.lookups["region"]["dev"], "us-west-2");
+
+        assert!(loader.load_from_str("aliases: [").is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_load_nonexistent_file() {
         // Create a path to a file that doesn't exist.
@@ -96,6 +133,33 @@ lookups:
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_load_duplicate_alias() -> Result<(), Error> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, "aliases:\n  gs: git status\n  gs: git show")?;
+
+        let loader = Loader::new();
+        let err = format!("{:?}", loader.load(&file.path().to_path_buf()).unwrap_err());
+
+        assert!(err.contains("duplicate alias 'gs'"));
+        assert!(err.contains(&format!("{:?}", file.path())));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_future_version() -> Result<(), Error> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, "defaults:\n  version: 99\naliases:\n  gs: git status")?;
+
+        let loader = Loader::new();
+        let err = format!("{:?}", loader.load(&file.path().to_path_buf()).unwrap_err());
+
+        assert!(err.contains("config version 99 is newer than this aka supports"));
+
+        Ok(())
+    }
+
     #[test]
     fn test_load_invalid_content() -> Result<(), Error> {
         // Create a mock spec file with invalid content.
@@ -1,5 +1,6 @@
-use eyre::Result;
-use serde::de::{self, MapAccess, Visitor};
+use eyre::{eyre, Result};
+use regex::Regex;
+use serde::de::{self, MapAccess, SeqAccess, Visitor};
 use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
 use std::fmt;
@@ -9,13 +10,57 @@ use super::alias::Alias;
 
 type Aliases = HashMap<String, Alias>;
 
+/// Characters the shell splits or interprets, so a name containing one can never be typed as a word
+const SHELL_METACHARS: &[char] = &[';', '&', '|', '<', '>', '(', ')', '$', '`', '"', '\'', '\\'];
+
+/// Placeholders resolved from git at query time, so they are not read from the environment at load
+pub const GIT_PLACEHOLDERS: &[&str] = &["BRANCH", "REPO"];
+
+/// `$VAR` and `${VAR}` references that `Spec::interpolate_env` expands, along with their escaped `\$` forms
+const ENV_REF_PATTERN: &str = r"\\?\$(?:\{([A-Za-z_][A-Za-z0-9_]*)\}|([A-Z_][A-Z0-9_]*))";
+
+/// The newest `defaults.version` this binary understands
+pub const CURRENT_VERSION: i32 = 1;
+
 const fn default_version() -> i32 {
-    1
+    CURRENT_VERSION
+}
+
+const fn default_true() -> bool {
+    true
+}
+
+fn default_privilege_command() -> String {
+    "sudo".to_string()
+}
+
+fn default_sudo_trigger() -> String {
+    "!".to_string()
+}
+
+const fn default_max_cmdline_len() -> usize {
+    64 * 1024
+}
+
+const fn default_max_expansions() -> usize {
+    1000
 }
 
-const fn default_defaults() -> Defaults {
+fn default_defaults() -> Defaults {
     Defaults {
         version: default_version(),
+        namespace_sep: None,
+        respect_comments: false,
+        sudo_wrap: default_true(),
+        sudo_wrap_paths: Vec::new(),
+        privilege_command: default_privilege_command(),
+        privilege_value_flags: None,
+        sudo_trigger: default_sudo_trigger(),
+        max_cmdline_len: default_max_cmdline_len(),
+        max_expansions: default_max_expansions(),
+        strict_names: false,
+        case_insensitive: false,
+        warn_shadow: false,
     }
 }
 
@@ -23,8 +68,78 @@ const fn default_defaults() -> Defaults {
 pub struct Defaults {
     #[serde(default = "default_version")]
     pub version: i32,
+
+    #[serde(default)]
+    pub namespace_sep: Option<String>,
+
+    #[serde(default)]
+    pub respect_comments: bool,
+
+    /// Wrap the command in `$(which ..)` when a line is run under sudo
+    #[serde(default = "default_true")]
+    pub sudo_wrap: bool,
+
+    /// When set, only wrap commands that resolve into one of these directories
+    #[serde(default)]
+    pub sudo_wrap_paths: Vec<String>,
+
+    /// The command that runs a line with privileges, e.g. `sudo` or `doas`
+    #[serde(default = "default_privilege_command")]
+    pub privilege_command: String,
+
+    /// Options of the privilege command that take a value; known for `sudo` and `doas` when unset
+    #[serde(default)]
+    pub privilege_value_flags: Option<Vec<String>>,
+
+    /// Typed at the end of a line to run it with the privilege command; empty disables it
+    #[serde(default = "default_sudo_trigger")]
+    pub sudo_trigger: String,
+
+    /// Lines longer than this many bytes are left unexpanded
+    #[serde(default = "default_max_cmdline_len")]
+    pub max_cmdline_len: usize,
+
+    /// At most this many substitutions are made in one query; the rest of the line is left as is
+    #[serde(default = "default_max_expansions")]
+    pub max_expansions: usize,
+
+    /// Fail to load, rather than warn, when an alias name can't be typed
+    #[serde(default)]
+    pub strict_names: bool,
+
+    /// Match typed alias names regardless of case; an exact-case name still wins
+    #[serde(default)]
+    pub case_insensitive: bool,
+
+    /// Warn on validate when an alias name is also a command on PATH
+    #[serde(default)]
+    pub warn_shadow: bool,
+}
+
+/// The compiled patterns of the regex aliases, by alias name in name order
+#[derive(Clone, Debug, Default)]
+pub struct Patterns(Vec<(String, Regex)>);
+
+impl Patterns {
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Regex)> {
+        self.0.iter().map(|(name, re)| (name.as_str(), re))
+    }
+}
+
+/// Patterns compiled from the same sources are equal
+impl PartialEq for Patterns {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len()
+            && self
+                .0
+                .iter()
+                .zip(&other.0)
+                .all(|((a, a_re), (b, b_re))| a == b && a_re.as_str() == b_re.as_str())
+    }
 }
 
+impl Eq for Patterns {}
+
 #[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
 pub struct Spec {
     #[serde(default = "default_defaults")]
@@ -35,6 +150,188 @@ pub struct Spec {
 
     #[serde(default)]
     pub lookups: HashMap<String, HashMap<String, String>>,
+
+    /// Filled by `compile_patterns` at load, so queries don't recompile them
+    #[serde(skip)]
+    pub patterns: Patterns,
+}
+
+/// Upgrade steps, where entry n - 1 turns a version n spec into version n + 1; version 1 is the first shape
+const MIGRATIONS: [fn(Spec) -> Result<Spec>; CURRENT_VERSION as usize - 1] = [];
+
+/// Upgrade a spec written for `from_version` to the shape of `CURRENT_VERSION`
+///
+/// # Errors
+///
+/// Will return `Err` if `from_version` is below 1 or newer than this binary supports.
+pub fn migrate_spec(mut spec: Spec, from_version: i32) -> Result<Spec> {
+    if from_version > CURRENT_VERSION {
+        return Err(eyre!(
+            "config version {from_version} is newer than this aka supports (up to {CURRENT_VERSION}); upgrade aka"
+        ));
+    }
+    if from_version < 1 {
+        return Err(eyre!("config version {from_version} is invalid; versions start at 1"));
+    }
+    for migrate in &MIGRATIONS[from_version as usize - 1..] {
+        spec = migrate(spec)?;
+    }
+    spec.defaults.version = CURRENT_VERSION;
+    Ok(spec)
+}
+
+impl Spec {
+    /// Check the spec for likely mistakes that don't prevent it from loading
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if there was a problem in processing an alias value,
+    /// or if `defaults.strict_names` is set and an alias name can't be typed.
+    pub fn validate(&self) -> Result<Vec<String>> {
+        let mut warnings = Vec::new();
+        let mut aliases: Vec<&Alias> = self.aliases.values().collect();
+        aliases.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let unusable: Vec<String> = aliases
+            .iter()
+            .filter(|alias| !alias.regex && !Self::is_usable_name(&alias.name))
+            .map(|alias| format!("alias '{}' has a name that can't be typed as a shell word", alias.name))
+            .collect();
+        if self.defaults.strict_names && !unusable.is_empty() {
+            return Err(eyre!(unusable.join("\n")));
+        }
+        warnings.extend(unusable);
+
+        for alias in aliases {
+            let indices = alias.positional_indices()?;
+            let missing: Vec<String> = (1..indices.last().copied().unwrap_or(0))
+                .filter(|index| !indices.contains(index))
+                .map(|index| format!("${index}"))
+                .collect();
+            if !missing.is_empty() {
+                warnings.push(format!(
+                    "alias '{}' skips positional parameter {}",
+                    alias.name,
+                    missing.join(", ")
+                ));
+            }
+
+            if let Some((_, re)) = self.patterns.iter().find(|(name, _)| *name == alias.name) {
+                let groups = re.captures_len() - 1;
+                if let Some(&last) = indices.last().filter(|&&last| last as usize > groups) {
+                    warnings.push(format!(
+                        "alias '{}' has only {} capture group(s), so ${} takes a positional argument",
+                        alias.name, groups, last
+                    ));
+                }
+            }
+
+            // A trailing open quote with `space: false` is the idiom for typing the rest, as in `gc: git commit -m "`
+            if let Some((quote, index)) = alias.open_quote() {
+                if alias.space || index + 1 != alias.value.trim_end().len() {
+                    warnings.push(format!("alias '{}' has an unbalanced {} quote", alias.name, quote));
+                }
+            }
+        }
+        Ok(warnings)
+    }
+
+    /// Compile the pattern of every regex alias
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a regex alias name is not a valid regular expression.
+    pub fn compile_patterns(&mut self) -> Result<()> {
+        let mut patterns = Vec::new();
+        for alias in self.aliases.values() {
+            if let Some(re) = alias.pattern()? {
+                patterns.push((alias.name.clone(), re));
+            }
+        }
+        patterns.sort_by(|(a, _), (b, _)| a.cmp(b));
+        self.patterns = Patterns(patterns);
+        Ok(())
+    }
+
+    /// A `|` prefix is allowed, since pipe aliases like `|c` are typed after a command
+    fn is_usable_name(name: &str) -> bool {
+        let name = name.strip_prefix('|').unwrap_or(name);
+        !name.is_empty() && !name.chars().any(|c| c.is_whitespace() || SHELL_METACHARS.contains(&c))
+    }
+
+    /// Split lookup keys like `prod|apps` into one entry per key, dropping empty keys
+    pub fn expand_lookup_keys(&mut self) {
+        for map in self.lookups.values_mut() {
+            let mut expanded = HashMap::new();
+            for (pattern, value) in map.iter() {
+                for key in pattern.split('|').filter(|key| !key.is_empty()) {
+                    expanded.insert(key.to_string(), value.clone());
+                }
+            }
+            *map = expanded;
+        }
+    }
+
+    /// Interpolate `$VAR` and `${VAR}` environment variable references in alias values
+    ///
+    /// Unbraced references must be uppercase so positionals (`$1`, `$@`) and keywords are left untouched.
+    /// The `GIT_PLACEHOLDERS` are also left untouched, for the query to resolve.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if an alias references an environment variable that is not set.
+    pub fn interpolate_env(&mut self) -> Result<()> {
+        let re = Regex::new(ENV_REF_PATTERN)?;
+        for alias in self.aliases.values_mut() {
+            let mut value = String::with_capacity(alias.value.len());
+            let mut last = 0;
+            for caps in re.captures_iter(&alias.value) {
+                let whole = caps.get(0).expect("capture 0 always matches");
+                if whole.as_str().starts_with('\\') {
+                    continue; // Escaped dollars are left for Alias::replace to emit literally
+                }
+                let var = caps
+                    .get(1)
+                    .or_else(|| caps.get(2))
+                    .expect("one group always matches")
+                    .as_str();
+                if GIT_PLACEHOLDERS.contains(&var) {
+                    continue;
+                }
+                let expanded = std::env::var(var).map_err(|_| {
+                    eyre!(
+                        "alias '{}' references unset environment variable '{var}', so the config can't load and no alias \
+                         will expand; set {var}, or write \\${var} for a literal dollar",
+                        alias.name
+                    )
+                })?;
+                value.push_str(&alias.value[last..whole.start()]);
+                value.push_str(&expanded);
+                last = whole.end();
+            }
+            value.push_str(&alias.value[last..]);
+            alias.value = value;
+        }
+        Ok(())
+    }
+}
+
+/// Escape the environment references in `value` that loading would expand, so they reach the shell as written
+///
+/// # Panics
+///
+/// Never; `ENV_REF_PATTERN` is a valid regex.
+#[must_use]
+pub fn escape_env_refs(value: &str) -> String {
+    let re = Regex::new(ENV_REF_PATTERN).expect("ENV_REF_PATTERN is a valid regex");
+    re.replace_all(value, |caps: &regex::Captures| {
+        let whole = &caps[0];
+        match whole.starts_with('\\') {
+            true => whole.to_string(),
+            false => format!("\\{whole}"),
+        }
+    })
+    .into_owned()
 }
 
 fn deserialize_alias_map<'de, D>(deserializer: D) -> Result<Aliases, D::Error>
@@ -48,7 +345,7 @@ where
         type Value = Alias;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("string or map")
+            formatter.write_str("string, list or map")
         }
 
         fn visit_str<E>(self, string: &str) -> Result<Alias, E>
@@ -58,6 +355,17 @@ where
             Alias::from_str(string).map_err(|_| E::custom("Unexpected Error"))
         }
 
+        fn visit_seq<S>(self, mut seq: S) -> Result<Alias, S::Error>
+        where
+            S: SeqAccess<'de>,
+        {
+            let mut parts: Vec<String> = Vec::new();
+            while let Some(part) = seq.next_element()? {
+                parts.push(part);
+            }
+            self.visit_str(&parts.join(" "))
+        }
+
         fn visit_map<M>(self, map: M) -> Result<Alias, M::Error>
         where
             M: MapAccess<'de>,
@@ -89,7 +397,7 @@ where
         {
             let mut aliases = Aliases::new();
             while let Some((name, AliasStringOrStruct(mut alias))) = map.next_entry::<String, AliasStringOrStruct>()? {
-                let names = if name.starts_with('|') || name.ends_with('|') {
+                let names = if alias.regex || name.starts_with('|') || name.ends_with('|') {
                     vec![&name[..]]
                 } else {
                     name.split('|').collect::<Vec<&str>>()
@@ -97,6 +405,9 @@ where
 
                 for name in names {
                     let name = name.to_string();
+                    if aliases.contains_key(&name) {
+                        return Err(de::Error::custom(format!("duplicate alias '{name}'")));
+                    }
                     alias.name = name.clone();
                     aliases.insert(name.clone(), alias.clone());
                 }
@@ -111,6 +422,44 @@ where
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_expand_lookup_keys() -> Result<()> {
+        let mut spec: Spec = serde_yaml::from_str(
+            r#"
+lookups:
+  region:
+    prod: us-east-1
+    staging|test|dev: us-west-2
+    '': nowhere
+    ops|: eu-west-1
+"#,
+        )?;
+        spec.expand_lookup_keys();
+
+        let region = &spec.lookups["region"];
+        assert_eq!(region["prod"], "us-east-1");
+        assert_eq!(region["staging"], "us-west-2");
+        assert_eq!(region["test"], "us-west-2");
+        assert_eq!(region["dev"], "us-west-2");
+        assert_eq!(region["ops"], "eu-west-1");
+        assert!(!region.contains_key(""));
+        assert_eq!(region.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_spec() -> Result<()> {
+        let spec: Spec = serde_yaml::from_str("defaults:\n  version: 1\naliases:\n  gs: git status\n")?;
+        let migrated = migrate_spec(spec.clone(), 1)?;
+        assert_eq!(migrated.defaults.version, CURRENT_VERSION);
+        assert_eq!(migrated.aliases, spec.aliases);
+
+        let err = migrate_spec(spec.clone(), CURRENT_VERSION + 1).unwrap_err().to_string();
+        assert!(err.contains("newer than this aka supports"));
+        assert!(migrate_spec(spec, 0).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_deserialize_alias_map_success() -> Result<(), eyre::Error> {
         let yaml = r#"
@@ -148,4 +497,245 @@ lookups:
 
         Ok(())
     }
+
+    #[test]
+    fn test_deserialize_list_value() -> Result<(), eyre::Error> {
+        let yaml = r#"
+aliases:
+  string: "cd app && make && deploy"
+  list: ["cd app", "&& make", "&& deploy"]
+  map:
+    value:
+      - cd app
+      - "&& make"
+      - "&& deploy"
+    space: false
+  multiline: |
+    cd app && make && deploy
+        "#;
+        let spec: Spec = serde_yaml::from_str(yaml)?;
+
+        assert_eq!(spec.aliases["list"].value, spec.aliases["string"].value);
+        assert_eq!(spec.aliases["map"].value, spec.aliases["string"].value);
+        assert!(!spec.aliases["map"].space);
+        assert_eq!(spec.aliases["multiline"].value.trim_end(), spec.aliases["string"].value);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_description() -> Result<(), eyre::Error> {
+        let yaml = r#"
+aliases:
+  gs: "git status"
+  gco:
+    value: "git checkout"
+    description: "switch branches"
+        "#;
+        let spec: Spec = serde_yaml::from_str(yaml)?;
+
+        assert_eq!(spec.aliases["gs"].description, "");
+        assert_eq!(spec.aliases["gco"].description, "switch branches");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_duplicate_alias() {
+        let yaml = r#"
+aliases:
+  gs: "git status"
+  gs: "git show"
+        "#;
+        let err = serde_yaml::from_str::<Spec>(yaml).unwrap_err().to_string();
+
+        assert!(err.contains("duplicate alias 'gs'"));
+    }
+
+    #[test]
+    fn test_deserialize_duplicate_alias_from_pipe_names() {
+        let yaml = r#"
+aliases:
+  gs|gst: "git status"
+  gst: "git stash"
+        "#;
+        let err = serde_yaml::from_str::<Spec>(yaml).unwrap_err().to_string();
+
+        assert!(err.contains("duplicate alias 'gst'"));
+    }
+
+    #[test]
+    fn test_validate_positional_gaps() -> Result<(), eyre::Error> {
+        let yaml = r#"
+aliases:
+  ok: "echo $1 $2"
+  gap: "echo $1 $3"
+  late: "echo $4"
+  all: "echo $@"
+        "#;
+        let spec: Spec = serde_yaml::from_str(yaml)?;
+
+        assert_eq!(
+            spec.validate()?,
+            vec![
+                "alias 'gap' skips positional parameter $2",
+                "alias 'late' skips positional parameter $1, $2, $3",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_captures_past_groups() -> Result<(), eyre::Error> {
+        let yaml = r#"
+aliases:
+  '^deploy-(.+)$':
+    value: "kubectl apply -f $1.yaml $2"
+    regex: true
+  '^logs-(.+)$':
+    value: "kubectl logs $1"
+    regex: true
+        "#;
+        let mut spec: Spec = serde_yaml::from_str(yaml)?;
+        spec.compile_patterns()?;
+
+        assert_eq!(
+            vec!["^deploy-(.+)$", "^logs-(.+)$"],
+            spec.patterns.iter().map(|(name, _)| name).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            spec.validate()?,
+            vec!["alias '^deploy-(.+)$' has only 1 capture group(s), so $2 takes a positional argument"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_names() -> Result<(), eyre::Error> {
+        let yaml = r#"
+aliases:
+  gs: "git status"
+  git st: "git status"
+  '|c':
+    value: '| xclip -sel clip'
+    global: true
+  'a;b': "echo"
+        "#;
+        let spec: Spec = serde_yaml::from_str(yaml)?;
+        assert_eq!(
+            spec.validate()?,
+            vec![
+                "alias 'a;b' has a name that can't be typed as a shell word",
+                "alias 'git st' has a name that can't be typed as a shell word",
+            ]
+        );
+
+        let strict = format!("defaults:\n  strict_names: true\n{yaml}");
+        let spec: Spec = serde_yaml::from_str(&strict)?;
+        let err = spec.validate().unwrap_err().to_string();
+        assert!(err.contains("alias 'git st'"));
+
+        let spec: Spec = serde_yaml::from_str("defaults:\n  strict_names: true\naliases:\n  gs: git status")?;
+        assert!(spec.validate()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_unbalanced_quotes() -> Result<(), eyre::Error> {
+        let yaml = r#"
+aliases:
+  gc:
+    value: 'git commit -m "'
+    space: false
+  ok: "echo 'hello world'"
+  broken: "echo 'hello world"
+  spaced: 'git commit -m "'
+        "#;
+        let spec: Spec = serde_yaml::from_str(yaml)?;
+
+        assert_eq!(
+            spec.validate()?,
+            vec![
+                "alias 'broken' has an unbalanced ' quote",
+                "alias 'spaced' has an unbalanced \" quote",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpolate_env_set_var() -> Result<(), eyre::Error> {
+        std::env::set_var("AKA_TEST_WORK_DIR", "/tmp/work");
+        let yaml = r#"
+aliases:
+  cw: "cd $AKA_TEST_WORK_DIR"
+  cwb: "cd ${AKA_TEST_WORK_DIR}/src"
+        "#;
+        let mut spec: Spec = serde_yaml::from_str(yaml)?;
+        spec.interpolate_env()?;
+
+        assert_eq!(spec.aliases["cw"].value, "cd /tmp/work");
+        assert_eq!(spec.aliases["cwb"].value, "cd /tmp/work/src");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpolate_env_unset_var() -> Result<(), eyre::Error> {
+        std::env::remove_var("AKA_TEST_UNSET_VAR");
+        let yaml = r#"
+aliases:
+  broken: "cd $AKA_TEST_UNSET_VAR"
+        "#;
+        let mut spec: Spec = serde_yaml::from_str(yaml)?;
+        let err = spec.interpolate_env().unwrap_err().to_string();
+
+        assert!(err.contains("broken"));
+        assert!(err.contains("AKA_TEST_UNSET_VAR"));
+        assert!(err.contains("no alias will expand"), "{err}");
+        assert!(err.contains(r"\$AKA_TEST_UNSET_VAR"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpolate_env_preserves_positionals() -> Result<(), eyre::Error> {
+        let yaml = r#"
+aliases:
+  pos: "echo $1 $2"
+  var: "echo $@"
+  kw: "echo $name"
+  esc: 'echo \$AKA_TEST_UNSET_ESCAPED'
+        "#;
+        let mut spec: Spec = serde_yaml::from_str(yaml)?;
+        spec.interpolate_env()?;
+
+        assert_eq!(spec.aliases["esc"].value, r"echo \$AKA_TEST_UNSET_ESCAPED");
+        assert_eq!(spec.aliases["pos"].value, "echo $1 $2");
+        assert_eq!(spec.aliases["var"].value, "echo $@");
+        assert_eq!(spec.aliases["kw"].value, "echo $name");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_escape_env_refs() {
+        assert_eq!(r"\$EDITOR $@", escape_env_refs("$EDITOR $@"));
+        assert_eq!(r"cd \${HOME}/src $1 $name", escape_env_refs("cd ${HOME}/src $1 $name"));
+        assert_eq!(r"echo \$HOME", escape_env_refs(r"echo \$HOME"));
+    }
+
+    #[test]
+    fn test_interpolate_env_skips_git_placeholders() -> Result<(), eyre::Error> {
+        let mut spec: Spec = serde_yaml::from_str("aliases:\n  push: git push origin $BRANCH ${REPO}\n")?;
+        spec.interpolate_env()?;
+
+        assert_eq!(spec.aliases["push"].value, "git push origin $BRANCH ${REPO}");
+
+        Ok(())
+    }
 }
@@ -1,28 +1,206 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use eyre::{eyre, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use shellexpand::tilde;
-use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::path::PathBuf;
-use std::process::exit;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, exit};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub mod cfg;
 use cfg::alias::Alias;
 use cfg::loader::Loader;
-use cfg::spec::Spec;
+use cfg::spec::{escape_env_refs, Spec, GIT_PLACEHOLDERS};
 
 const CONFIGS: &[&str] = &["./aka.yml", "~/.aka.yml", "~/.config/aka/aka.yml"];
+const EXIT_CONFIG_NOT_FOUND: i32 = 2;
+const EXIT_CONFIG_INVALID: i32 = 3;
+const EXIT_QUERY_UNCHANGED: i32 = 10;
+const EXIT_QUERY_EMPTY: i32 = 11;
+const LOG_FILE: &str = "~/aka.log";
+const LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const LOG_KEEP: usize = 3;
+const HISTORY_FILE: &str = "~/aka.history";
+const HISTORY_MAX_ENTRIES: usize = 1000;
+const MAX_LOOKUP_DEPTH: usize = 8;
 
-fn divine_config() -> Result<PathBuf> {
-    let configs: Vec<PathBuf> = CONFIGS
+/// A `lookup:name[key]` token whose key holds no further token
+static LOOKUP_TOKEN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"lookup:([^\[\]\s]+)\[([^\[\]]*)\]").expect("LOOKUP_TOKEN is a valid regex"));
+
+const MAX_EXPANSION_DEPTH: usize = 16;
+/// Options of sudo that take a value, so the value isn't mistaken for the command
+const SUDO_VALUE_FLAGS: &[&str] = &[
+    "-C",
+    "-D",
+    "-g",
+    "-p",
+    "-R",
+    "-r",
+    "-T",
+    "-t",
+    "-U",
+    "-u",
+    "--chdir",
+    "--close-from",
+    "--group",
+    "--other-user",
+    "--prompt",
+    "--role",
+    "--type",
+    "--user",
+];
+/// Options of doas that take a value
+const DOAS_VALUE_FLAGS: &[&str] = &["-C", "-u"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LogLevel {
+    Info,
+    Warn,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+fn log_format(value: Option<&str>) -> LogFormat {
+    match value {
+        Some(format) if format.eq_ignore_ascii_case("json") => LogFormat::Json,
+        _ => LogFormat::Text,
+    }
+}
+
+fn format_log_line(format: LogFormat, ts: f64, level: LogLevel, message: &str) -> String {
+    let level = match level {
+        LogLevel::Info => "info",
+        LogLevel::Warn => "warn",
+    };
+    match format {
+        LogFormat::Text if level == "info" => message.to_string(),
+        LogFormat::Text => format!("{level}: {message}"),
+        LogFormat::Json => serde_json::json!({
+            "ts": ts,
+            "level": level,
+            "target": "aka",
+            "msg": message,
+        })
+        .to_string(),
+    }
+}
+
+/// Rename `path` to `path.1` (shifting older logs up to `path.keep`) once it exceeds `max_bytes`
+fn rotate_log_if_needed(path: &Path, max_bytes: u64, keep: usize) -> Result<bool> {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.len() > max_bytes => {}
+        _ => return Ok(false),
+    }
+    let rotated = |index: usize| PathBuf::from(format!("{}.{index}", path.display()));
+    for index in (1..keep).rev() {
+        if rotated(index).exists() {
+            fs::rename(rotated(index), rotated(index + 1))?;
+        }
+    }
+    if keep > 0 {
+        fs::rename(path, rotated(1))?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(true)
+}
+
+fn log(level: LogLevel, message: &str) -> Result<()> {
+    if std::env::var("AKA_LOG").is_ok() {
+        let format = log_format(std::env::var("AKA_LOG_FORMAT").ok().as_deref());
+        let ts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+        let path = PathBuf::from(tilde(LOG_FILE).as_ref());
+        let max_bytes = std::env::var("AKA_LOG_MAX_BYTES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(LOG_MAX_BYTES);
+        rotate_log_if_needed(&path, max_bytes, LOG_KEEP)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        writeln!(file, "{}", format_log_line(format, ts, level, message))?;
+    }
+    Ok(())
+}
+
+/// One query recorded in the history file, as a JSON line
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct HistoryEntry {
+    ts: f64,
+    input: String,
+    output: String,
+}
+
+fn read_history(path: &Path) -> Result<Vec<HistoryEntry>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+/// Append `entry` to the history at `path`, keeping only the newest `max_entries`
+fn append_history(path: &Path, entry: HistoryEntry, max_entries: usize) -> Result<()> {
+    let mut entries = read_history(path)?;
+    entries.push(entry);
+    let start = entries.len().saturating_sub(max_entries);
+    let mut content = String::new();
+    for entry in &entries[start..] {
+        content.push_str(&serde_json::to_string(entry)?);
+        content.push('\n');
+    }
+    fs::write(path, content)?;
+    Ok(())
+}
+
+/// The history file queries are recorded to, when `AKA_HISTORY` is set
+fn history_file() -> Option<PathBuf> {
+    std::env::var("AKA_HISTORY")
+        .ok()
+        .map(|_| PathBuf::from(tilde(HISTORY_FILE).as_ref()))
+}
+
+fn record_history(path: &Path, input: &str, output: &str) -> Result<()> {
+    let max_entries = std::env::var("AKA_HISTORY_MAX")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(HISTORY_MAX_ENTRIES);
+    let entry = HistoryEntry {
+        ts: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64(),
+        input: input.to_string(),
+        output: output.to_string(),
+    };
+    append_history(path, entry, max_entries)
+}
+
+fn default_configs() -> Vec<PathBuf> {
+    CONFIGS
         .iter()
         .map(tilde)
         .map(|file| PathBuf::from(file.as_ref()))
-        .collect();
+        .collect()
+}
+
+fn divine_config_from(configs: &[PathBuf]) -> Result<PathBuf> {
     for config in configs {
         if config.exists() {
-            return Ok(config);
+            return Ok(config.clone());
         }
     }
     Err(eyre!("couldn't divine a config!"))
@@ -35,6 +213,57 @@ fn test_config(file: &PathBuf) -> Result<PathBuf> {
     Err(eyre!("config {:?} not found!", file))
 }
 
+/// Load the spec from the resolved config, or from `stdin` when the config is `-`
+fn load_spec(config: &Option<PathBuf>, mut stdin: impl Read) -> Result<Spec> {
+    let loader = Loader::new();
+    match config {
+        Some(file) if file.as_os_str() == "-" => {
+            let mut content = String::new();
+            stdin.read_to_string(&mut content)?;
+            loader.load_from_str(&content)
+        }
+        _ => loader.load(&resolve_config(config)?),
+    }
+}
+
+/// Reject commands that read stdin themselves, or need the config file on disk, when the config comes from stdin
+fn check_stdin_config(config: &Option<PathBuf>, command: &Option<Command>) -> Result<()> {
+    if config.as_ref().is_none_or(|file| file.as_os_str() != "-") {
+        return Ok(());
+    }
+    let conflict = match command {
+        Some(Command::Query(query_opts)) if query_opts.batch => "query --batch",
+        Some(Command::Query(query_opts)) if query_opts.cmdline.as_deref() == Some("-") => "query -",
+        Some(Command::AcceptLine(accept_opts)) if accept_opts.cmdline == "-" => "__accept_line -",
+        Some(Command::Import(_)) => "import",
+        Some(Command::Stats(_)) => "stats",
+        Some(Command::Edit) => "edit",
+        Some(Command::Config(_)) => "config",
+        Some(Command::Doctor) => "doctor",
+        _ => return Ok(()),
+    };
+    Err(eyre!(
+        "--config - cannot be combined with {conflict}; the config already takes stdin"
+    ))
+}
+
+/// The config to load: `--config` wins over `AKA_CONFIG`, which wins over searching `CONFIGS`
+fn resolve_config(config: &Option<PathBuf>) -> Result<PathBuf> {
+    let from_env = std::env::var("AKA_CONFIG")
+        .ok()
+        .filter(|file| !file.is_empty())
+        .map(|file| PathBuf::from(tilde(&file).as_ref()));
+    resolve_config_from(config, from_env, &default_configs())
+}
+
+fn resolve_config_from(config: &Option<PathBuf>, from_env: Option<PathBuf>, configs: &[PathBuf]) -> Result<PathBuf> {
+    match (config, from_env) {
+        (Some(file), _) => test_config(file),
+        (None, Some(file)) => test_config(&file).map_err(|err| eyre!("{err} (set by AKA_CONFIG)")),
+        (None, None) => divine_config_from(configs),
+    }
+}
+
 mod built_info {
     include!(concat!(env!("OUT_DIR"), "/git_describe.rs"));
 }
@@ -44,12 +273,15 @@ mod built_info {
 #[command(version = built_info::GIT_DESCRIBE)]
 #[command(author = "Scott A. Idler <scott.a.idler@gmail.com>")]
 #[command(arg_required_else_help = true)]
-#[command(after_help = "set env var AKA_LOG to turn on logging to ~/aka.log")]
+#[command(
+    after_help = "set env var AKA_CONFIG to pick the config when --config isn't given, before searching ./aka.yml, ~/.aka.yml and ~/.config/aka/aka.yml
+set env var AKA_LOG to turn on logging to ~/aka.log (AKA_LOG_FORMAT=json for json lines)"
+)]
 struct AkaOpts {
     #[clap(short, long, help = "is entry an [e]nd [o]f [l]ine?")]
     eol: bool,
 
-    #[clap(short, long)]
+    #[clap(short, long, help = "config file to load; '-' reads it from stdin")]
     config: Option<PathBuf>,
 
     #[clap(subcommand)]
@@ -63,11 +295,76 @@ enum Command {
 
     #[clap(name = "query", about = "query for aka substitutions")]
     Query(QueryOpts),
+
+    #[clap(name = "preview", about = "show how a command line expands on space and on enter")]
+    Preview(PreviewOpts),
+
+    #[clap(name = "export", about = "export aka aliases as plain shell definitions")]
+    Export(ExportOpts),
+
+    #[clap(name = "import", about = "import shell alias definitions into the aka config")]
+    Import(ImportOpts),
+
+    #[clap(name = "search", about = "search aka aliases by name or value")]
+    Search(SearchOpts),
+
+    #[clap(name = "config", about = "show which config aka loads and whether it is valid")]
+    Config(ConfigOpts),
+
+    #[clap(name = "edit", about = "open the config in $EDITOR and validate it on exit")]
+    Edit,
+
+    #[clap(name = "doctor", about = "check for common setup problems and suggest fixes")]
+    Doctor,
+
+    #[clap(
+        name = "reverse",
+        about = "find the aliases whose value is or starts with the given text"
+    )]
+    Reverse(ReverseOpts),
+
+    #[clap(name = "stats", about = "summarize the aliases and lookups in the config")]
+    Stats(StatsOpts),
+
+    #[clap(name = "history", about = "show recent queries recorded when AKA_HISTORY is set")]
+    History(HistoryOpts),
+
+    #[clap(
+        name = "completions",
+        about = "print a script completing alias names for bash or fish"
+    )]
+    Completions(CompletionsOpts),
+
+    #[clap(name = "__complete_aliases", hide = true)]
+    CompleteAliases(CompleteAliasesOpts),
+
+    #[clap(name = "__complete_value", hide = true)]
+    CompleteValue(CompleteValueOpts),
+
+    #[clap(name = "__accept_line", hide = true)]
+    AcceptLine(AcceptLineOpts),
 }
 
 #[derive(Parser)]
 struct QueryOpts {
-    cmdline: String,
+    #[clap(long, help = "also expand aliases inside double-quoted strings")]
+    expand_quoted: bool,
+
+    #[clap(long, help = "treat every alias as global; may cause surprising expansions")]
+    anywhere: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "cmdline",
+        help = "expand each line of stdin, printing one result per line"
+    )]
+    batch: bool,
+
+    #[clap(
+        required_unless_present = "batch",
+        help = "command line to expand; '-' reads one line from stdin"
+    )]
+    cmdline: Option<String>,
 }
 
 #[derive(Parser)]
@@ -75,101 +372,617 @@ struct ListOpts {
     #[clap(short, long, help = "list global aliases only")]
     global: bool,
 
+    #[clap(short, long, help = "include '|' pipe aliases, which are hidden by default")]
+    all: bool,
+
+    #[clap(long, help = "include disabled aliases, which are hidden by default")]
+    show_disabled: bool,
+
+    #[clap(short, long, help = "show each alias's description above it")]
+    verbose: bool,
+
+    #[clap(
+        short,
+        long = "tag",
+        value_name = "TAG",
+        help = "list aliases with this tag; repeat to match any"
+    )]
+    tags: Vec<String>,
+
+    #[clap(short, long, help = "truncate values to fit this many columns; defaults to $COLUMNS")]
+    width: Option<usize>,
+
+    #[clap(long, help = "output aliases as json")]
+    json: bool,
+
+    #[clap(short, long, value_enum, default_value = "name", help = "field to sort aliases by")]
+    sort: SortKey,
+
+    #[clap(long, help = "match patterns as globs with '*' and '?' instead of as name prefixes")]
+    glob: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "json",
+        help = "render each alias with a template like '{name} = {value}'; fields are name, value, global, space, description and tags"
+    )]
+    format: Option<String>,
+
     patterns: Vec<String>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum SortKey {
+    Name,
+    Value,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Shell {
+    Zsh,
+    Bash,
+}
+
+#[derive(Parser)]
+struct ExportOpts {
+    #[clap(short, long, value_enum, default_value = "zsh", help = "shell syntax to emit")]
+    shell: Shell,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum CompletionShell {
+    Bash,
+    Fish,
+}
+
+#[derive(Parser)]
+struct CompletionsOpts {
+    #[clap(value_enum, help = "shell to emit a completion script for")]
+    shell: CompletionShell,
+}
+
+#[derive(Parser)]
+struct ImportOpts {
+    #[clap(help = "file of `alias name='value'` lines; reads stdin if omitted or -")]
+    file: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+struct SearchOpts {
+    #[clap(help = "case-insensitive text to find in alias names and values")]
+    query: Option<String>,
+}
+
+#[derive(Parser)]
+struct PreviewOpts {
+    #[clap(help = "command line to expand both ways")]
+    cmdline: String,
+}
+
+#[derive(Parser)]
+struct ReverseOpts {
+    #[clap(help = "expansion to trace back to its aliases")]
+    value: String,
+}
+
+#[derive(Parser)]
+struct StatsOpts {
+    #[clap(long, help = "output stats as json")]
+    json: bool,
+}
+
+#[derive(Parser)]
+struct HistoryOpts {
+    #[clap(short = 'n', long, help = "show only this many of the most recent queries")]
+    count: Option<usize>,
+
+    #[clap(long, help = "output history entries as json")]
+    json: bool,
+}
+
+#[derive(Parser)]
+struct ConfigOpts {
+    #[clap(long, help = "print the resolved config path (default)")]
+    path: bool,
+
+    #[clap(long, help = "load the config and report alias/lookup counts or the parse error")]
+    validate: bool,
+
+    #[clap(
+        long,
+        value_name = "RUNS",
+        num_args = 0..=1,
+        default_missing_value = "5",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "load the config RUNS times (default 5) and report how long loading takes"
+    )]
+    timings: Option<u32>,
+}
+
+#[derive(Parser)]
+struct CompleteAliasesOpts {
+    #[clap(default_value = "")]
+    prefix: String,
+}
+
+#[derive(Parser)]
+struct CompleteValueOpts {
+    name: String,
+}
+
+#[derive(Parser)]
+struct AcceptLineOpts {
+    #[clap(help = "command line being run; '-' reads it from stdin")]
+    cmdline: String,
+}
+
+/// Why `aka query` did or did not produce an expansion
+#[derive(Debug, PartialEq, Eq)]
+enum ReplaceStatus {
+    Expanded(String),
+    Unchanged,
+    Empty,
+}
+
+impl ReplaceStatus {
+    fn exit_code(&self) -> i32 {
+        match self {
+            ReplaceStatus::Expanded(_) => 0,
+            ReplaceStatus::Unchanged => EXIT_QUERY_UNCHANGED,
+            ReplaceStatus::Empty => EXIT_QUERY_EMPTY,
+        }
+    }
+}
+
+/// How aka finds commands, so sudo wrapping can be decided without depending on the system in tests
+trait CommandRunner: fmt::Debug {
+    /// Resolve `command` against the user's PATH
+    fn which(&self, command: &str) -> Option<PathBuf>;
+
+    /// Run git with `args` in the working directory, returning its trimmed output when it succeeds
+    fn git(&self, args: &[&str]) -> Option<String>;
+}
+
+#[derive(Debug)]
+struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn which(&self, command: &str) -> Option<PathBuf> {
+        which_in(command, std::env::var_os("PATH")?)
+    }
+
+    fn git(&self, args: &[&str]) -> Option<String> {
+        let output = process::Command::new("git")
+            .args(args)
+            .stderr(process::Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        let stdout = String::from_utf8(output.stdout).ok()?;
+        Some(stdout.trim().to_string()).filter(|stdout| !stdout.is_empty())
+    }
+}
+
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 struct AKA {
     pub eol: bool,
+    pub expand_quoted: bool,
+    pub anywhere: bool,
+    /// Where the query runs, for aliases with a `when` condition
+    pub cwd: Option<PathBuf>,
     pub spec: Spec,
+    /// Lowercased alias names to their configured names, built when `defaults.case_insensitive` is set
+    lowercase_index: HashMap<String, String>,
+    runner: Box<dyn CommandRunner>,
+    /// Lookups already made through the runner, so repeated sudo wrapping doesn't re-probe
+    which_memo: RefCell<HashMap<String, Option<PathBuf>>>,
+    /// Git placeholders already resolved in this query, so each runs git at most once per query
+    git_memo: RefCell<HashMap<String, Option<String>>>,
+    /// Where queries are recorded, if anywhere
+    history: Option<PathBuf>,
 }
 
 impl AKA {
     pub fn new(eol: bool, config: &Option<PathBuf>) -> Result<Self> {
-        let config = match &config {
-            Some(file) => test_config(file)?,
-            None => divine_config()?,
-        };
-        let loader = Loader::new();
-        let mut spec = loader.load(&config)?;
-
-        // Expand keys in lookups
-        for (_, map) in spec.lookups.iter_mut() {
-            let mut expanded = HashMap::new();
-            for (pattern, value) in map.iter() {
-                let keys: Vec<&str> = pattern.split('|').collect();
-                for key in keys {
-                    expanded.insert(key.to_string(), value.clone());
-                }
+        Self::from_spec(eol, load_spec(config, io::stdin().lock())?)
+    }
+
+    fn from_spec(eol: bool, spec: Spec) -> Result<Self> {
+        for warning in spec.validate()? {
+            log(LogLevel::Warn, &warning)?;
+        }
+
+        let mut lowercase_index = HashMap::new();
+        if spec.defaults.case_insensitive {
+            // Names that differ only in case resolve to the first in name order
+            let mut names: Vec<&String> = spec.aliases.keys().collect();
+            names.sort();
+            for name in names {
+                lowercase_index
+                    .entry(name.to_lowercase())
+                    .or_insert_with(|| name.clone());
             }
-            *map = expanded;
         }
 
-        Ok(Self { eol, spec })
+        Ok(Self {
+            eol,
+            expand_quoted: false,
+            anywhere: false,
+            cwd: std::env::current_dir().ok(),
+            spec,
+            lowercase_index,
+            runner: Box::new(SystemRunner),
+            which_memo: RefCell::new(HashMap::new()),
+            git_memo: RefCell::new(HashMap::new()),
+            history: history_file(),
+        })
+    }
+
+    #[cfg(test)]
+    fn with_runner(self, runner: impl CommandRunner + 'static) -> Self {
+        Self {
+            runner: Box::new(runner),
+            which_memo: RefCell::new(HashMap::new()),
+            git_memo: RefCell::new(HashMap::new()),
+            ..self
+        }
     }
 
-    pub fn use_alias(&self, alias: &Alias, pos: usize) -> bool {
-        if alias.is_variadic() && !self.eol {
+    pub fn use_alias(&self, alias: &Alias, pos: usize, eol: bool) -> bool {
+        if alias.is_variadic() && !eol {
             false
-        } else if pos == 0 {
+        } else if alias.global_only {
+            pos > 0
+        } else if pos == 0 || self.anywhere {
             true
         } else {
             alias.global
         }
     }
 
-    fn split_respecting_quotes(cmdline: &str) -> Vec<String> {
+    /// Split `cmdline` into args, returning alongside them the whitespace that preceded each arg.
+    /// A trailing unquoted sudo `trigger` becomes its own arg.
+    fn split_respecting_quotes(cmdline: &str, trigger: &str) -> (Vec<String>, Vec<String>) {
         let mut args = Vec::new();
-        let mut start = 0;
-        let mut in_quotes = false;
-        let chars: Vec<char> = cmdline.chars().collect();
-        for index in 0..chars.len() {
-            if chars[index] == '"' {
-                in_quotes = !in_quotes;
-            } else if chars[index] == ' ' && !in_quotes {
-                if start != index {
+        let mut seps = Vec::new();
+        let mut sep_start = 0;
+        let mut start: Option<usize> = None;
+        let mut quote: Option<char> = None;
+        let mut chars = cmdline.char_indices().peekable();
+        while let Some((index, c)) = chars.next() {
+            let in_quotes = quote.is_some();
+            if c == '\\' && quote != Some('\'') {
+                // A backslash escapes the next char, except inside single quotes where it is literal
+                start.get_or_insert(index);
+                chars.next();
+            } else if c == '"' || c == '\'' {
+                match quote {
+                    None => quote = Some(c),
+                    Some(q) if q == c => quote = None,
+                    Some(_) => {}
+                }
+                start.get_or_insert(index);
+            } else if c == ' ' && !in_quotes {
+                if let Some(start) = start.take() {
+                    seps.push(cmdline[sep_start..start].to_string());
                     args.push(cmdline[start..index].to_string());
+                    sep_start = index;
                 }
-                start = index + 1;
-            } else if chars[index] == '!' && !in_quotes && index == chars.len() - 1 {
-                if start != index {
+            } else if !in_quotes && !trigger.is_empty() && cmdline[index..].trim_end() == trigger {
+                if let Some(start) = start.take() {
+                    seps.push(cmdline[sep_start..start].to_string());
                     args.push(cmdline[start..index].to_string());
+                    sep_start = index;
                 }
-                args.push(String::from("!"));
-                start = index + 1;
+                seps.push(cmdline[sep_start..index].to_string());
+                args.push(trigger.to_string());
+                return (args, seps);
+            } else {
+                start.get_or_insert(index);
             }
         }
-        if start != chars.len() {
+        if let Some(start) = start {
+            seps.push(cmdline[sep_start..start].to_string());
             args.push(cmdline[start..].to_string());
         }
-        args
+        (args, seps)
+    }
+
+    fn is_assignment(arg: &str) -> bool {
+        match arg.split_once('=') {
+            Some((name, _)) => {
+                let mut chars = name.chars();
+                chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            }
+            None => false,
+        }
+    }
+
+    fn skip_assignments(args: &[String], pos: usize) -> usize {
+        pos + args[pos..].iter().take_while(|arg| Self::is_assignment(arg)).count()
+    }
+
+    /// Options of the privilege command that take a value
+    fn privilege_value_flags(&self) -> Vec<&str> {
+        let defaults = &self.spec.defaults;
+        match &defaults.privilege_value_flags {
+            Some(flags) => flags.iter().map(String::as_str).collect(),
+            None => match defaults.privilege_command.as_str() {
+                "sudo" => SUDO_VALUE_FLAGS.to_vec(),
+                "doas" => DOAS_VALUE_FLAGS.to_vec(),
+                _ => Vec::new(),
+            },
+        }
+    }
+
+    /// Index of the command, past leading assignments and any privilege prefix (e.g. `sudo`) with its flags
+    fn command_position(&self, args: &[String]) -> usize {
+        let privilege = &self.spec.defaults.privilege_command;
+        let value_flags = self.privilege_value_flags();
+        let mut pos = Self::skip_assignments(args, 0);
+        if args.get(pos).is_some_and(|arg| arg == privilege) {
+            pos += 1;
+            while let Some(arg) = args.get(pos) {
+                if arg == "--" {
+                    pos += 1;
+                    break;
+                } else if !arg.starts_with('-') {
+                    break;
+                }
+                pos += if value_flags.contains(&arg.as_str()) { 2 } else { 1 };
+            }
+            pos = Self::skip_assignments(args, pos.min(args.len()));
+        }
+        pos
+    }
+
+    /// Whether an alias is enabled and its `when` condition holds here
+    fn is_active(&self, alias: &Alias) -> bool {
+        alias.enabled && alias.when.as_ref().is_none_or(|when| when.holds(self.cwd.as_deref()))
+    }
+
+    fn find_alias(&self, arg: &str) -> Result<Option<Alias>> {
+        let folded = self.lowercase_index.get(&arg.to_lowercase());
+        for name in std::iter::once(arg).chain(folded.map(String::as_str)) {
+            if let Some(alias) = self
+                .spec
+                .aliases
+                .get(name)
+                .filter(|alias| self.is_active(alias) && !alias.regex)
+            {
+                return Ok(Some(alias.clone()));
+            }
+        }
+        // Regex aliases are only tried after exact names, in name order so matching is deterministic
+        for (name, re) in self.spec.patterns.iter() {
+            let Some(alias) = self.spec.aliases.get(name).filter(|alias| self.is_active(alias)) else {
+                continue;
+            };
+            if let Some(matched) = alias.captures(re, arg) {
+                return Ok(Some(matched));
+            }
+        }
+        Ok(None)
     }
 
     fn perform_lookup(&self, key: &str, lookup: &str) -> Option<String> {
         self.spec.lookups.get(lookup)?.get(key).cloned()
     }
 
+    fn resolve_lookups(&self, arg: &str) -> Result<String> {
+        // Innermost tokens resolve first, so lookup:zone[lookup:region[prod]] chains
+        let mut resolved = arg.to_string();
+        for _ in 0..MAX_LOOKUP_DEPTH {
+            let next = LOOKUP_TOKEN
+                .replace_all(&resolved, |caps: &regex::Captures| {
+                    let (lookup, key) = (&caps[1], &caps[2]);
+                    self.perform_lookup(key, lookup)
+                        .or_else(|| {
+                            let (key, default) = key.split_once(':')?;
+                            self.perform_lookup(key, lookup).or_else(|| Some(default.to_string()))
+                        })
+                        .unwrap_or_else(|| caps[0].to_string())
+                })
+                .into_owned();
+            if next == resolved {
+                break;
+            }
+            resolved = next;
+        }
+        Ok(resolved)
+    }
+
+    fn expand_quoted_spans(&self, arg: &str) -> Result<String> {
+        let mut result = String::with_capacity(arg.len());
+        let mut copied = 0;
+        // The open quote and its byte offset, scanned with the same escaping as `split_respecting_quotes`
+        let mut quote: Option<(char, usize)> = None;
+        let mut chars = arg.char_indices();
+        while let Some((index, c)) = chars.next() {
+            match (quote, c) {
+                (Some(('\'', _)), '\'') => quote = None,
+                (Some(('\'', _)), _) => {}
+                (_, '\\') => {
+                    chars.next();
+                }
+                (None, '"' | '\'') => quote = Some((c, index)),
+                (Some((_, open)), '"') => {
+                    let inner = &arg[open + 1..index];
+                    let expanded = self.replace_with_mode(inner, false)?;
+                    result.push_str(&arg[copied..=open]);
+                    if expanded.is_empty() {
+                        result.push_str(inner);
+                    } else {
+                        result.push_str(expanded.strip_suffix(' ').unwrap_or(&expanded));
+                    }
+                    result.push('"');
+                    copied = index + 1;
+                    quote = None;
+                }
+                _ => {}
+            }
+        }
+        result.push_str(&arg[copied..]);
+        Ok(result)
+    }
+
+    /// Whether `command` should be resolved with `$(which ..)`, since root's PATH may not contain it
+    fn needs_sudo_wrapping(&self, command: &str) -> bool {
+        let defaults = &self.spec.defaults;
+        if !defaults.sudo_wrap {
+            return false;
+        }
+        if defaults.sudo_wrap_paths.is_empty() {
+            return true;
+        }
+        self.which(command)
+            .is_some_and(|path| in_wrap_paths(&path, &defaults.sudo_wrap_paths))
+    }
+
+    fn which(&self, command: &str) -> Option<PathBuf> {
+        self.which_memo
+            .borrow_mut()
+            .entry(command.to_string())
+            .or_insert_with(|| self.runner.which(command))
+            .clone()
+    }
+
+    fn git_placeholder(&self, name: &str) -> Option<String> {
+        self.git_memo
+            .borrow_mut()
+            .entry(name.to_string())
+            .or_insert_with(|| match name {
+                "BRANCH" => self.runner.git(&["rev-parse", "--abbrev-ref", "HEAD"]),
+                _ => self
+                    .runner
+                    .git(&["rev-parse", "--show-toplevel"])
+                    .and_then(|top| Some(Path::new(&top).file_name()?.to_string_lossy().into_owned())),
+            })
+            .clone()
+    }
+
+    /// Substitute `$BRANCH` and `$REPO` in an alias value, leaving them as is outside a git repository
+    fn resolve_git_placeholders(&self, value: &str) -> Result<String> {
+        if !GIT_PLACEHOLDERS.iter().any(|name| value.contains(name)) {
+            return Ok(value.to_string());
+        }
+        let names = GIT_PLACEHOLDERS.join("|");
+        let re = Regex::new(&format!(r"\\?\$(?:\{{({names})\}}|({names})\b)"))?;
+        let mut resolved = String::with_capacity(value.len());
+        let mut last = 0;
+        for caps in re.captures_iter(value) {
+            let whole = caps.get(0).expect("capture 0 always matches");
+            if whole.as_str().starts_with('\\') {
+                continue; // Escaped dollars are left for Alias::replace to emit literally
+            }
+            let name = caps
+                .get(1)
+                .or_else(|| caps.get(2))
+                .expect("one group always matches")
+                .as_str();
+            match self.git_placeholder(name) {
+                Some(replacement) => {
+                    resolved.push_str(&value[last..whole.start()]);
+                    resolved.push_str(&replacement);
+                    last = whole.end();
+                }
+                None => log(
+                    LogLevel::Warn,
+                    &format!("leaving ${name} as is, not in a git repository"),
+                )?,
+            }
+        }
+        resolved.push_str(&value[last..]);
+        Ok(resolved)
+    }
+
     pub fn replace(&self, cmdline: &str) -> Result<String> {
-        let mut pos: usize = 0;
-        let mut space = " ";
+        self.replace_with_mode(cmdline, self.eol)
+    }
+
+    fn replace_status(&self, cmdline: &str) -> Result<ReplaceStatus> {
+        self.replace_status_with_mode(cmdline, self.eol)
+    }
+
+    /// Log a query and its result, and add it to the history
+    fn record_query(&self, cmdline: &str, result: &str) -> Result<()> {
+        log(LogLevel::Info, &format!("'{}' -> '{}'", cmdline, result))?;
+        match &self.history {
+            Some(path) => record_history(path, cmdline, result),
+            None => Ok(()),
+        }
+    }
+
+    /// The expansion of a line as enter runs it, whatever `eol` this was built with
+    fn accept_line(&self, cmdline: &str) -> Result<ReplaceStatus> {
+        self.replace_status_with_mode(cmdline, true)
+    }
+
+    fn replace_status_with_mode(&self, cmdline: &str, eol: bool) -> Result<ReplaceStatus> {
+        if cmdline.trim().is_empty() {
+            return Ok(ReplaceStatus::Empty);
+        }
+        let result = self.replace_with_mode(cmdline, eol)?;
+        Ok(if result.is_empty() {
+            ReplaceStatus::Unchanged
+        } else {
+            ReplaceStatus::Expanded(result)
+        })
+    }
+
+    pub fn replace_with_mode(&self, cmdline: &str, eol: bool) -> Result<String> {
+        // The branch or repo may have changed since the last query, as between lines of a batch
+        self.git_memo.borrow_mut().clear();
+        let max_len = self.spec.defaults.max_cmdline_len;
+        if cmdline.len() > max_len {
+            log(
+                LogLevel::Warn,
+                &format!(
+                    "not expanding a {} byte line, over max_cmdline_len {}",
+                    cmdline.len(),
+                    max_len
+                ),
+            )?;
+            return Ok(String::new());
+        }
+
         let mut replaced = false;
         let mut sudo = false;
-        let mut args = Self::split_respecting_quotes(cmdline);
+        let trigger = self.spec.defaults.sudo_trigger.as_str();
+        let (mut args, mut seps) = Self::split_respecting_quotes(cmdline, trigger);
+
+        // A standalone `#` starts a comment, which is passed through without substitution
+        let (mut comment_args, mut comment_seps) = (Vec::new(), Vec::new());
+        if self.spec.defaults.respect_comments {
+            if let Some(index) = args.iter().position(|arg| arg == "#") {
+                comment_args = args.split_off(index);
+                comment_seps = seps.split_off(index);
+            }
+        }
+        let mut cmd = self.command_position(&args);
 
-        if self.eol && !args.is_empty() {
+        if eol && !trigger.is_empty() {
             if let Some(last_arg) = args.last() {
-                if last_arg == "!" || last_arg.ends_with("!") {
+                if last_arg.ends_with(trigger) {
                     args.pop();
+                    seps.pop();
                     sudo = true;
-                } else if last_arg.starts_with("!") {
-                    let next_arg = last_arg[1..].to_string();
-                    args[0] = next_arg;
+                } else if let Some(next_arg) = last_arg.strip_prefix(trigger) {
+                    let next_arg = next_arg.to_string();
+                    // The trigger word itself may be the value of a privilege flag, leaving no command to replace
+                    if cmd >= args.len() {
+                        cmd = 0;
+                    }
+                    args[cmd] = next_arg;
                     replaced = true;
 
-                    let mut i = 1;
+                    let mut i = cmd + 1;
                     while i < args.len() {
                         if args[i].starts_with("-") {
                             args.remove(i);
+                            seps.remove(i);
                         } else if args[i] == "|" || args[i] == ">" || args[i] == "<" {
                             break;
                         } else {
@@ -177,37 +990,101 @@ impl AKA {
                         }
                     }
                     args.pop();
+                    seps.pop();
                 }
             }
         }
 
+        // The chain of alias names that produced each arg, used to break expansion cycles
+        let mut chains: Vec<Vec<String>> = vec![Vec::new(); args.len()];
+        let max_expansions = self.spec.defaults.max_expansions;
+        let mut expansions = 0;
+        let mut pos = cmd; // Leading NAME=value assignments are passed through untouched
         while pos < args.len() {
-            let current_arg = args[pos].clone(); // Clone to avoid borrowing conflicts
+            if expansions >= max_expansions {
+                log(
+                    LogLevel::Warn,
+                    &format!("stopped after max_expansions {max_expansions}, leaving the rest of the line as is"),
+                )?;
+                break;
+            }
+            if self.expand_quoted && args[pos].contains('"') {
+                let expanded = self.expand_quoted_spans(&args[pos])?;
+                if expanded != args[pos] {
+                    args[pos] = expanded;
+                    replaced = true;
+                }
+            }
 
-            // Perform lookup replacement logic
-            if current_arg.starts_with("lookup:") && current_arg.contains("[") && current_arg.ends_with("]") {
-                let parts: Vec<&str> = current_arg.splitn(2, '[').collect();
-                let lookup = parts[0].trim_start_matches("lookup:");
-                let key = parts[1].trim_end_matches("]");
-                if let Some(replacement) = self.perform_lookup(key, lookup) {
-                    args[pos] = replacement.clone(); // Replace in args
+            // An explicit `alias:NAME` token inlines that alias's value, which is then processed in turn
+            if let Some(name) = args[pos].strip_prefix("alias:") {
+                let mut chain = chains[pos].clone();
+                match self.find_alias(name)? {
+                    Some(alias) if !chain.contains(&alias.name) && chain.len() < MAX_EXPANSION_DEPTH => {
+                        chain.push(alias.name.clone());
+                        let value = self.resolve_git_placeholders(&alias.value)?;
+                        let (values, mut value_seps) = Self::split_respecting_quotes(&value, trigger);
+                        if let Some(first) = value_seps.first_mut() {
+                            *first = seps[pos].clone();
+                        }
+                        chains.splice(pos..=pos, vec![chain; values.len()]);
+                        seps.splice(pos..=pos, value_seps);
+                        args.splice(pos..=pos, values);
+                        replaced = true;
+                        expansions += 1;
+                        continue;
+                    }
+                    Some(alias) => log(
+                        LogLevel::Warn,
+                        &format!(
+                            "not expanding '{}' after alias chain {}",
+                            alias.name,
+                            chain.join(" -> ")
+                        ),
+                    )?,
+                    None => log(LogLevel::Warn, &format!("'{}' references an unknown alias", args[pos]))?,
+                }
+            }
+
+            // Perform lookup replacement logic, then evaluate the resolved value as an alias
+            if args[pos].starts_with("lookup:") && args[pos].ends_with(']') {
+                let replacement = self.resolve_lookups(&args[pos])?;
+                if replacement != args[pos] {
+                    args[pos] = replacement;
                     replaced = true;
-                    continue; // Reevaluate the current position after replacement
                 }
             }
 
+            let current_arg = args[pos].clone(); // Clone to avoid borrowing conflicts
+
             let mut remainders: Vec<String> = args[pos + 1..].to_vec();
-            let (value, count) = match self.spec.aliases.get(&current_arg) {
-                Some(alias) if self.use_alias(alias, pos) => {
-                    if (alias.global && cmdline.contains(&alias.value))
-                        || (!alias.global && pos == 0 && cmdline.starts_with(&alias.value))
+            let mut chain = chains[pos].clone();
+            let (value, count) = match self.find_alias(&current_arg)? {
+                Some(alias) if self.use_alias(&alias, pos - cmd, eol) => {
+                    if chain.contains(&alias.name) || chain.len() >= MAX_EXPANSION_DEPTH {
+                        log(
+                            LogLevel::Warn,
+                            &format!(
+                                "not expanding '{}' after alias chain {}",
+                                alias.name,
+                                chain.join(" -> ")
+                            ),
+                        )?;
+                        (current_arg.clone(), 0)
+                    } else if (alias.is_global() && cmdline.contains(&alias.value))
+                        || (!alias.is_global() && pos == cmd && args[cmd..].join(" ").starts_with(&alias.value))
                     {
                         (current_arg.clone(), 0)
                     } else {
-                        space = if alias.space { " " } else { "" };
+                        let alias = Alias {
+                            value: self.resolve_git_placeholders(&alias.value)?,
+                            ..alias
+                        };
                         let (v, c) = alias.replace(&mut remainders)?;
                         if v != alias.name {
                             replaced = true;
+                            expansions += 1;
+                            chain.push(alias.name.clone());
                         }
                         (v, c)
                     }
@@ -215,25 +1092,58 @@ impl AKA {
                 Some(_) | None => (current_arg.clone(), 0),
             };
 
+            let expanded = value != current_arg;
             let beg = pos + 1;
             let end = beg + count;
 
-            if space.is_empty() {
-                args.drain(beg..end);
-            } else {
-                args.drain(beg..end);
+            args.drain(beg..end);
+            seps.drain(beg..end);
+            chains.drain(beg..end);
+            let (values, mut value_seps) = Self::split_respecting_quotes(&value, trigger);
+            if let Some(first) = value_seps.first_mut() {
+                *first = seps[pos].clone(); // The expansion takes the place of the original arg
+            }
+            chains.splice(pos..=pos, vec![chain; values.len()]);
+            seps.splice(pos..=pos, value_seps);
+            args.splice(pos..=pos, values);
+            // An expansion that starts with an explicit reference resolves it before moving on
+            if !(expanded && args.get(pos).is_some_and(|arg| arg.starts_with("alias:"))) {
+                pos += 1;
             }
-            args.splice(pos..=pos, Self::split_respecting_quotes(&value));
-            pos += 1;
         }
 
         if sudo {
-            args[0] = format!("$(which {})", args[0]);
-            args.insert(0, "sudo".to_string());
+            let cmd = self.command_position(&args);
+            if cmd < args.len() && self.needs_sudo_wrapping(&args[cmd]) {
+                args[cmd] = format!("$(which {})", args[cmd]);
+            }
+            let privilege = &self.spec.defaults.privilege_command;
+            if !args[..cmd].iter().any(|arg| arg == privilege) {
+                args.insert(0, privilege.clone());
+                seps.insert(0, String::new());
+                if seps.len() > 1 {
+                    seps.swap(0, 1);
+                    seps[1] = " ".to_string();
+                }
+            }
         }
 
+        chains.extend(vec![Vec::new(); comment_args.len()]);
+        args.append(&mut comment_args);
+        seps.append(&mut comment_seps);
+
+        // The trailing space follows the alias that produced the final arg
+        let space = match chains.last().and_then(|chain| chain.last()) {
+            Some(name) => match self.find_alias(name)? {
+                Some(alias) if !alias.space => "",
+                _ => " ",
+            },
+            None => " ",
+        };
+
         let result = if replaced || sudo {
-            format!("{}{}", args.join(" "), space)
+            let line: String = seps.iter().zip(&args).map(|(sep, arg)| format!("{sep}{arg}")).collect();
+            format!("{line}{space}")
         } else {
             String::new()
         };
@@ -242,51 +1152,805 @@ impl AKA {
     }
 }
 
-fn print_alias(alias: &Alias) {
-    if alias.value.contains('\n') {
-        println!("{}: |\n  {}", alias.name, alias.value.replace("\n", "\n  "));
-    } else {
-        println!("{}: {}", alias.name, alias.value);
+/// Resolve `command` against `paths` the way the shell would, without spawning a process
+fn which_in(command: &str, paths: impl AsRef<OsStr>) -> Option<PathBuf> {
+    if command.contains('/') {
+        let path = PathBuf::from(command);
+        return path.is_file().then_some(path);
     }
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(command))
+        .find(|path| path.is_file())
 }
 
-fn execute() -> Result<i32> {
-    let aka_opts = AkaOpts::parse();
-    let aka = AKA::new(aka_opts.eol, &aka_opts.config)?;
-    if let Some(command) = aka_opts.command {
-        match command {
-            Command::Query(query_opts) => {
-                let result = aka.replace(&query_opts.cmdline)?;
-                if std::env::var("AKA_LOG").is_ok() {
-                    let mut file = OpenOptions::new()
-                        .create(true)
-                        .write(true)
-                        .append(true)
-                        .open("/home/saidler/aka.log")?;
-                    writeln!(file, "'{}' -> '{}'", query_opts.cmdline, result)?;
-                }
-                println!("{result}");
-            }
-            Command::List(list_opts) => {
-                let mut aliases: Vec<Alias> = aka.spec.aliases.values().cloned().collect();
-                aliases.sort_by_key(|a| a.name.clone());
-
-                if list_opts.global {
-                    aliases = aliases.into_iter().filter(|alias| alias.global).collect();
-                }
+fn in_wrap_paths(path: &Path, dirs: &[String]) -> bool {
+    path.parent()
+        .is_some_and(|parent| dirs.iter().any(|dir| parent == Path::new(tilde(dir).as_ref())))
+}
 
-                if list_opts.patterns.is_empty() {
+/// The command line to query, read as a single line from `input` when given as `-`
+fn read_cmdline(cmdline: &str, mut input: impl BufRead) -> Result<String> {
+    if cmdline != "-" {
+        return Ok(cmdline.to_string());
+    }
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// Expand every line of `input` with the one loaded `aka`, an empty result meaning unchanged
+fn query_batch(aka: &AKA, input: impl BufRead) -> Result<Vec<String>> {
+    input
+        .lines()
+        .map(|line| {
+            let line = line?;
+            let result = aka.replace(&line)?;
+            aka.record_query(&line, &result)?;
+            Ok(result)
+        })
+        .collect()
+}
+
+fn filter_aliases(spec: &Spec, list_opts: &ListOpts) -> Vec<Alias> {
+    let mut aliases: Vec<Alias> = spec.aliases.values().cloned().collect();
+    match list_opts.sort {
+        SortKey::Name => aliases.sort_by(|a, b| a.name.cmp(&b.name)),
+        SortKey::Value => aliases.sort_by(|a, b| a.value.cmp(&b.value).then_with(|| a.name.cmp(&b.name))),
+    }
+
+    if !list_opts.all && !list_opts.show_disabled {
+        aliases.retain(|alias| alias.enabled);
+    }
+
+    if list_opts.global {
+        aliases.retain(Alias::is_global);
+    } else if !list_opts.all {
+        aliases.retain(|alias| !alias.name.starts_with('|'));
+    }
+
+    if !list_opts.tags.is_empty() {
+        aliases.retain(|alias| alias.tags.iter().any(|tag| list_opts.tags.contains(tag)));
+    }
+
+    if !list_opts.patterns.is_empty() {
+        aliases.retain(|alias| {
+            list_opts.patterns.iter().any(|pattern| match list_opts.glob {
+                true => glob_match(pattern, &alias.name),
+                false => alias.name.starts_with(pattern),
+            })
+        });
+    }
+    aliases
+}
+
+/// Match `name` against a glob where `*` is any run of characters and `?` is exactly one
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn export_alias(alias: &Alias, shell: Shell) -> Result<Option<String>> {
+    // Regex names aren't valid shell names, and a shell definition can't re-check a `when` condition
+    if alias.name.starts_with('|') || alias.regex || alias.when.is_some() {
+        return Ok(None);
+    }
+    if alias.is_variadic() || !alias.positionals()?.is_empty() {
+        // The shell still sees `\$` inside a function body, so only the alias form needs unescaping
+        let body = match alias.value.split_whitespace().next() {
+            // A function named after the command it wraps would otherwise call itself
+            Some(first) if first == alias.name => format!("command {}", alias.value),
+            _ => alias.value.clone(),
+        };
+        return Ok(Some(format!(
+            "{}() {{\n    {}\n}}",
+            alias.name,
+            body.replace('\n', "\n    ")
+        )));
+    }
+    let value = alias.literal_value().replace('\'', r"'\''");
+    match (alias.is_global(), shell) {
+        (false, _) => Ok(Some(format!("alias {}='{}'", alias.name, value))),
+        (true, Shell::Zsh) => Ok(Some(format!("alias -g {}='{}'", alias.name, value))),
+        (true, Shell::Bash) => Ok(None),
+    }
+}
+
+fn unquote_shell_word(word: &str) -> String {
+    let mut result = String::new();
+    let mut chars = word.chars();
+    let mut quote: Option<char> = None;
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => result.push(c),
+            (Some(_), '\\') => match chars.next() {
+                Some(next @ ('"' | '\\' | '$' | '`')) => result.push(next),
+                Some(next) => {
+                    result.push('\\');
+                    result.push(next);
+                }
+                None => result.push('\\'),
+            },
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '\\') => result.extend(chars.next()),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+fn parse_shell_alias(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    let definition = line.strip_prefix("alias ").unwrap_or(line).trim_start();
+    let (name, value) = definition.split_once('=')?;
+    let name = unquote_shell_word(name);
+    if name.is_empty() || name.starts_with('-') || name.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((name, unquote_shell_word(value)))
+}
+
+fn insert_aliases(config: &str, entries: &[(String, String)]) -> Result<String> {
+    let mut lines: Vec<String> = config.lines().map(String::from).collect();
+    let index = lines.iter().position(|line| line.trim() == "aliases:");
+    let indent = match index {
+        Some(index) => lines[index + 1..]
+            .iter()
+            .find(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|line| line.len() - line.trim_start().len())
+            .filter(|&child| child > lines[index].len() - lines[index].trim_start().len())
+            .unwrap_or(lines[index].len() - lines[index].trim_start().len() + 2),
+        None => 2,
+    };
+    let mut block = Vec::new();
+    for (name, value) in entries {
+        let entry = BTreeMap::from([(name, value)]);
+        for line in serde_yaml::to_string(&entry)?.lines() {
+            block.push(format!("{}{}", " ".repeat(indent), line));
+        }
+    }
+    match index {
+        Some(index) => {
+            lines.splice(index + 1..index + 1, block);
+        }
+        None if config.contains("aliases:") => {
+            return Err(eyre!("couldn't find a block-style `aliases:` section to import into"));
+        }
+        None => {
+            lines.push("aliases:".to_string());
+            lines.extend(block);
+        }
+    }
+    Ok(format!("{}\n", lines.join("\n")))
+}
+
+fn import_aliases(config: &str, spec: &Spec, input: &str) -> Result<(String, Vec<String>, Vec<String>)> {
+    let mut imported: Vec<(String, String)> = Vec::new();
+    let mut skipped = Vec::new();
+    for (name, value) in input.lines().filter_map(parse_shell_alias) {
+        if spec.aliases.contains_key(&name) || imported.iter().any(|(n, _)| n == &name) {
+            skipped.push(name);
+        } else {
+            // Shell aliases expand `$VAR` when run, so keep it for the shell rather than interpolating at load
+            imported.push((name, escape_env_refs(&value)));
+        }
+    }
+    let content = if imported.is_empty() {
+        config.to_string()
+    } else {
+        let content = insert_aliases(config, &imported)?;
+        Loader::new()
+            .load_from_str(&content)
+            .map_err(|err| eyre!("importing would leave a config that can't load: {err}"))?;
+        content
+    };
+    Ok((content, imported.into_iter().map(|(name, _)| name).collect(), skipped))
+}
+
+fn is_subsequence(needle: &str, haystack: &str) -> bool {
+    let mut haystack = haystack.chars();
+    needle.chars().all(|c| haystack.any(|h| h == c))
+}
+
+fn search_aliases(spec: &Spec, query: &str) -> Vec<Alias> {
+    let query = query.to_lowercase();
+    let mut aliases: Vec<Alias> = spec
+        .aliases
+        .values()
+        // Like `aka ls`, leave out disabled aliases
+        .filter(|alias| alias.enabled)
+        .filter(|alias| {
+            let name = alias.name.to_lowercase();
+            name.contains(&query)
+                || alias.value.to_lowercase().contains(&query)
+                || alias.description.to_lowercase().contains(&query)
+                || is_subsequence(&query, &name)
+        })
+        .cloned()
+        .collect();
+    aliases.sort_by(|a, b| a.name.cmp(&b.name));
+    aliases
+}
+
+/// What `cmdline` expands to when space is pressed and when enter is, empty where it is left unchanged
+fn preview(aka: &AKA, cmdline: &str) -> Result<(String, String)> {
+    let space = aka.replace_with_mode(cmdline, false)?;
+    let enter = aka.replace_with_mode(cmdline, true)?;
+    // Enter's expansion is the one that would run
+    aka.record_query(cmdline, &enter)?;
+    Ok((space, enter))
+}
+
+/// Names of the enabled aliases whose value is `value`, then those whose value starts with it
+fn reverse_aliases(spec: &Spec, value: &str) -> Vec<String> {
+    let mut matches: Vec<(bool, &str)> = spec
+        .aliases
+        .values()
+        .filter(|alias| alias.enabled && alias.value.starts_with(value))
+        .map(|alias| (alias.value != value, alias.name.as_str()))
+        .collect();
+    matches.sort();
+    matches.into_iter().map(|(_, name)| name.to_string()).collect()
+}
+
+fn complete_aliases(spec: &Spec, prefix: &str) -> Vec<String> {
+    let mut names: Vec<String> = spec
+        .aliases
+        .values()
+        .filter(|alias| alias.enabled && !alias.regex && !alias.name.starts_with('|') && alias.name.starts_with(prefix))
+        .map(|alias| match &spec.defaults.namespace_sep {
+            Some(sep) if !sep.is_empty() => match alias.name[prefix.len()..].find(sep.as_str()) {
+                Some(index) => alias.name[..prefix.len() + index + sep.len()].to_string(),
+                None => alias.name.clone(),
+            },
+            _ => alias.name.clone(),
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn complete_value(spec: &Spec, name: &str) -> Option<String> {
+    spec.aliases
+        .get(name)
+        .filter(|alias| alias.enabled && !alias.regex)
+        .map(|alias| alias.value.clone())
+}
+
+/// Cut `text` to at most `width` chars, marking the cut with an ellipsis
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+    match width {
+        0 => String::new(),
+        _ => text.chars().take(width - 1).chain(['…']).collect(),
+    }
+}
+
+fn format_alias(alias: &Alias, verbose: bool, width: Option<usize>) -> String {
+    let fit = |text: &str, used: usize| match width {
+        Some(width) => truncate(text, width.saturating_sub(used)),
+        None => text.to_string(),
+    };
+    let mut lines = Vec::new();
+    if verbose && !alias.description.is_empty() {
+        lines.push(format!("# {}", fit(&alias.description, 2)));
+    }
+    if alias.value.contains('\n') {
+        lines.push(format!("{}: |", alias.name));
+        for line in alias.value.split('\n') {
+            lines.push(format!("  {}", fit(line, 2)));
+        }
+    } else {
+        let used = alias.name.chars().count() + 2;
+        lines.push(format!("{}: {}", alias.name, fit(&alias.value, used)));
+    }
+    lines.join("\n")
+}
+
+/// Substitute `{field}` placeholders in `template` in a single pass, so substituted text is never rescanned.
+/// Unknown placeholders are kept as literal text.
+fn format_alias_with_template(alias: &Alias, template: &str) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        rest = &rest[open..];
+        let Some(close) = rest.find('}') else { break };
+        let field = match &rest[1..close] {
+            "name" => alias.name.clone(),
+            "value" => alias.value.clone(),
+            "global" => alias.is_global().to_string(),
+            "space" => alias.space.to_string(),
+            "description" => alias.description.clone(),
+            "tags" => alias.tags.join(","),
+            _ => rest[..=close].to_string(),
+        };
+        rendered.push_str(&field);
+        rest = &rest[close + 1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+fn print_alias(alias: &Alias, verbose: bool, width: Option<usize>) {
+    println!("{}", format_alias(alias, verbose, width));
+}
+
+fn validate_config(config: &Path) -> Result<(Spec, Vec<String>)> {
+    let spec = Loader::new().load(&config.to_path_buf())?;
+    let mut warnings = spec.validate()?;
+    if spec.defaults.warn_shadow {
+        warnings.extend(shadow_warnings(&spec, &SystemRunner));
+    }
+    Ok((spec, warnings))
+}
+
+/// Warnings for aliases whose name is also a command on PATH, in name order
+fn shadow_warnings(spec: &Spec, runner: &dyn CommandRunner) -> Vec<String> {
+    let mut aliases: Vec<&Alias> = spec
+        .aliases
+        .values()
+        .filter(|alias| alias.enabled && !alias.regex && !alias.name.starts_with('|'))
+        .collect();
+    aliases.sort_by(|a, b| a.name.cmp(&b.name));
+    aliases
+        .into_iter()
+        .filter_map(|alias| {
+            let path = runner.which(&alias.name)?;
+            let wraps = alias.value.split_whitespace().next() == Some(alias.name.as_str());
+            Some(match wraps {
+                true => format!(
+                    "alias '{}' wraps {}; typing it never runs the bare command",
+                    alias.name,
+                    path.display()
+                ),
+                false => format!(
+                    "alias '{}' shadows {} with '{}'",
+                    alias.name,
+                    path.display(),
+                    alias.value
+                ),
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum EditOutcome {
+    Unchanged,
+    Changed,
+    Invalid(String),
+}
+
+fn hash_file(path: &Path) -> Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    fs::read(path)?.hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+fn check_edit(config: &Path, before: u64) -> Result<EditOutcome> {
+    if hash_file(config)? == before {
+        return Ok(EditOutcome::Unchanged);
+    }
+    match validate_config(config) {
+        Ok(_) => Ok(EditOutcome::Changed),
+        Err(err) => Ok(EditOutcome::Invalid(format!("{err:?}"))),
+    }
+}
+
+fn execute_edit(config: &Option<PathBuf>) -> Result<i32> {
+    let config = resolve_config(config)?;
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or("vi");
+    let args: Vec<&str> = words.collect();
+    let before = hash_file(&config)?;
+    loop {
+        let status = process::Command::new(program).args(&args).arg(&config).status()?;
+        if !status.success() {
+            return Err(eyre!("editor {:?} exited with {}", editor, status));
+        }
+        match check_edit(&config, before)? {
+            EditOutcome::Unchanged => println!("{} unchanged", config.display()),
+            EditOutcome::Changed => println!("{} updated", config.display()),
+            EditOutcome::Invalid(err) => {
+                eprintln!("warning: {} no longer loads:\n{err}", config.display());
+                eprint!("re-open the editor? [Y/n] ");
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer)?;
+                if !answer.trim().eq_ignore_ascii_case("n") {
+                    continue;
+                }
+                return Ok(EXIT_CONFIG_INVALID);
+            }
+        }
+        return Ok(0);
+    }
+}
+
+const BASH_COMPLETIONS: &str = r#"# aka alias-name completion for bash; source this from ~/.bashrc
+_aka_complete_aliases() {
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    local IFS=$'\n'
+    COMPREPLY=($(aka __complete_aliases "$cur" 2>/dev/null))
+}
+complete -o nospace -F _aka_complete_aliases aka
+"#;
+
+const FISH_COMPLETIONS: &str = r#"# aka alias-name completion for fish; save as ~/.config/fish/completions/aka.fish
+complete -c aka -f -a '(aka __complete_aliases (commandline -ct) 2>/dev/null)'
+"#;
+
+fn completion_script(shell: CompletionShell) -> &'static str {
+    match shell {
+        CompletionShell::Bash => BASH_COMPLETIONS,
+        CompletionShell::Fish => FISH_COMPLETIONS,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct Stats {
+    config: PathBuf,
+    config_bytes: u64,
+    config_hash: String,
+    aliases: usize,
+    global: usize,
+    variadic: usize,
+    regex: usize,
+    disabled: usize,
+    lookups: usize,
+}
+
+fn compute_stats(spec: &Spec, config: &Path) -> Result<Stats> {
+    let count = |predicate: fn(&Alias) -> bool| spec.aliases.values().filter(|alias| predicate(alias)).count();
+    Ok(Stats {
+        config: config.to_path_buf(),
+        config_bytes: fs::metadata(config)?.len(),
+        config_hash: format!("{:016x}", hash_file(config)?),
+        aliases: spec.aliases.len(),
+        global: count(Alias::is_global),
+        variadic: count(Alias::is_variadic),
+        regex: count(|alias| alias.regex),
+        disabled: count(|alias| !alias.enabled),
+        lookups: spec.lookups.len(),
+    })
+}
+
+fn print_stats(stats: &Stats) {
+    println!(
+        "config: {} ({} bytes, hash {})",
+        stats.config.display(),
+        stats.config_bytes,
+        stats.config_hash
+    );
+    println!("aliases: {}", stats.aliases);
+    println!("  global: {}", stats.global);
+    println!("  variadic: {}", stats.variadic);
+    println!("  regex: {}", stats.regex);
+    println!("  disabled: {}", stats.disabled);
+    println!("lookups: {}", stats.lookups);
+}
+
+/// One diagnostic of `aka doctor`; `detail` says what was found, or how to fix it on failure
+#[derive(Debug, PartialEq, Eq)]
+struct Check {
+    name: &'static str,
+    passed: bool,
+    detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, result: std::result::Result<String, String>) -> Self {
+        let passed = result.is_ok();
+        let detail = result.unwrap_or_else(|hint| hint);
+        Self { name, passed, detail }
+    }
+}
+
+fn check_config_found(config: &Option<PathBuf>) -> Check {
+    let result = resolve_config(config)
+        .map(|path| path.display().to_string())
+        .map_err(|err| format!("{err} create one at one of {}", CONFIGS.join(", ")));
+    Check::new("config found", result)
+}
+
+fn check_config_parses(config: &Path) -> Check {
+    let result = validate_config(config)
+        .map(|(spec, warnings)| format!("{} aliases, {} warnings", spec.aliases.len(), warnings.len()))
+        .map_err(|err| format!("{err}; see `aka config --validate` and fix it with `aka edit`"));
+    Check::new("config parses", result)
+}
+
+fn check_log_writable(enabled: bool, path: &Path) -> Check {
+    let result = match enabled {
+        false => Ok("AKA_LOG is unset, so nothing is logged".to_string()),
+        true => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map(|_| path.display().to_string())
+            .map_err(|err| {
+                format!(
+                    "{}: {err}; every query fails until it is writable or AKA_LOG is unset",
+                    path.display()
+                )
+            }),
+    };
+    Check::new("log writable", result)
+}
+
+fn check_on_path(paths: Option<std::ffi::OsString>) -> Check {
+    let result = paths
+        .and_then(|paths| which_in("aka", paths))
+        .map(|path| path.display().to_string())
+        .ok_or_else(|| "aka is not on PATH; the shell integration runs `aka query` on every space".to_string());
+    Check::new("aka on PATH", result)
+}
+
+/// Log, record and print the outcome of a query, returning its exit code
+fn print_query(aka: &AKA, cmdline: &str, status: ReplaceStatus) -> Result<i32> {
+    let result = match &status {
+        ReplaceStatus::Expanded(result) => result.as_str(),
+        ReplaceStatus::Unchanged | ReplaceStatus::Empty => "",
+    };
+    aka.record_query(cmdline, result)?;
+    println!("{result}");
+    Ok(status.exit_code())
+}
+
+fn execute_history(history_opts: &HistoryOpts) -> Result<i32> {
+    let entries = read_history(Path::new(tilde(HISTORY_FILE).as_ref()))?;
+    let start = history_opts
+        .count
+        .map_or(0, |count| entries.len().saturating_sub(count));
+    let entries = &entries[start..];
+    if history_opts.json {
+        println!("{}", serde_json::to_string_pretty(entries)?);
+    } else {
+        for entry in entries {
+            println!("{:.3} '{}' -> '{}'", entry.ts, entry.input, entry.output);
+        }
+    }
+    Ok(0)
+}
+
+fn execute_doctor(config: &Option<PathBuf>) -> Result<i32> {
+    let mut checks = vec![check_config_found(config)];
+    if let Ok(config) = resolve_config(config) {
+        checks.push(check_config_parses(&config));
+    }
+    checks.push(check_log_writable(
+        std::env::var("AKA_LOG").is_ok(),
+        Path::new(tilde(LOG_FILE).as_ref()),
+    ));
+    checks.push(check_on_path(std::env::var_os("PATH")));
+    for check in &checks {
+        let status = if check.passed { "ok" } else { "FAIL" };
+        println!("[{status:>4}] {}: {}", check.name, check.detail);
+    }
+    Ok(if checks.iter().all(|check| check.passed) { 0 } else { 1 })
+}
+
+/// Shortest and mean wall time of loading a config
+#[derive(Debug)]
+struct LoadTimings {
+    runs: u32,
+    min: Duration,
+    avg: Duration,
+}
+
+/// Load `config` `runs` times, the same way a query does, and time each load
+fn time_config_load(config: &Path, runs: u32) -> Result<LoadTimings> {
+    let runs = runs.max(1);
+    let mut durations = Vec::new();
+    for _ in 0..runs {
+        let start = Instant::now();
+        Loader::new().load(&config.to_path_buf())?;
+        durations.push(start.elapsed());
+    }
+    let total: Duration = durations.iter().sum();
+    Ok(LoadTimings {
+        runs,
+        min: durations.iter().min().copied().unwrap_or_default(),
+        avg: total / runs,
+    })
+}
+
+fn execute_config(config_opts: &ConfigOpts, config: &Option<PathBuf>) -> Result<i32> {
+    let config = match resolve_config(config) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("error: {err}");
+            return Ok(EXIT_CONFIG_NOT_FOUND);
+        }
+    };
+    if config_opts.path || (!config_opts.validate && config_opts.timings.is_none()) {
+        println!("{}", config.display());
+    }
+    if config_opts.validate {
+        match validate_config(&config) {
+            Ok((spec, warnings)) => {
+                println!("valid: {} aliases, {} lookups", spec.aliases.len(), spec.lookups.len());
+                for warning in warnings {
+                    println!("warning: {warning}");
+                }
+            }
+            Err(err) => {
+                println!("invalid: {err:?}");
+                return Ok(EXIT_CONFIG_INVALID);
+            }
+        }
+    }
+    if let Some(runs) = config_opts.timings {
+        match time_config_load(&config, runs) {
+            Ok(timings) => println!(
+                "load: min {:.3}ms, avg {:.3}ms over {} runs",
+                timings.min.as_secs_f64() * 1000.0,
+                timings.avg.as_secs_f64() * 1000.0,
+                timings.runs
+            ),
+            Err(err) => {
+                println!("invalid: {err:?}");
+                return Ok(EXIT_CONFIG_INVALID);
+            }
+        }
+    }
+    Ok(0)
+}
+
+fn execute() -> Result<i32> {
+    let aka_opts = AkaOpts::parse();
+    check_stdin_config(&aka_opts.config, &aka_opts.command)?;
+    // Config introspection must work even when the config fails to load
+    match &aka_opts.command {
+        Some(Command::Config(config_opts)) => return execute_config(config_opts, &aka_opts.config),
+        Some(Command::Edit) => return execute_edit(&aka_opts.config),
+        Some(Command::Doctor) => return execute_doctor(&aka_opts.config),
+        Some(Command::History(history_opts)) => return execute_history(history_opts),
+        Some(Command::Completions(completions_opts)) => {
+            print!("{}", completion_script(completions_opts.shell));
+            return Ok(0);
+        }
+        _ => {}
+    }
+    let aka = AKA::new(aka_opts.eol, &aka_opts.config)?;
+    if let Some(command) = aka_opts.command {
+        match command {
+            Command::Query(query_opts) => {
+                let aka = AKA {
+                    expand_quoted: query_opts.expand_quoted,
+                    anywhere: query_opts.anywhere,
+                    ..aka
+                };
+                if query_opts.batch {
+                    for result in query_batch(&aka, io::stdin().lock())? {
+                        println!("{result}");
+                    }
+                    return Ok(0);
+                }
+                let cmdline = read_cmdline(query_opts.cmdline.as_deref().unwrap_or_default(), io::stdin().lock())?;
+                return print_query(&aka, &cmdline, aka.replace_status(&cmdline)?);
+            }
+            Command::AcceptLine(accept_opts) => {
+                let cmdline = read_cmdline(&accept_opts.cmdline, io::stdin().lock())?;
+                return print_query(&aka, &cmdline, aka.accept_line(&cmdline)?);
+            }
+            Command::List(list_opts) => {
+                let aliases = filter_aliases(&aka.spec, &list_opts);
+                if list_opts.json {
+                    println!("{}", serde_json::to_string_pretty(&aliases)?);
+                } else if let Some(template) = &list_opts.format {
                     for alias in aliases {
-                        print_alias(&alias);
+                        println!("{}", format_alias_with_template(&alias, template));
                     }
                 } else {
+                    let width = list_opts.width.or_else(|| std::env::var("COLUMNS").ok()?.parse().ok());
                     for alias in aliases {
-                        if list_opts.patterns.iter().any(|pattern| alias.name.starts_with(pattern)) {
-                            print_alias(&alias);
-                        }
+                        print_alias(&alias, list_opts.verbose, width);
+                    }
+                }
+            }
+            Command::Export(export_opts) => {
+                let mut aliases: Vec<Alias> = aka.spec.aliases.values().filter(|a| a.enabled).cloned().collect();
+                aliases.sort_by_key(|a| a.name.clone());
+                for alias in aliases {
+                    match export_alias(&alias, export_opts.shell)? {
+                        Some(line) => println!("{line}"),
+                        None => eprintln!("warning: skipping alias {:?}; it can't be exported", alias.name),
+                    }
+                }
+            }
+            Command::Import(import_opts) => {
+                let input = match import_opts.file {
+                    Some(file) if file.as_os_str() != "-" => fs::read_to_string(file)?,
+                    _ => {
+                        let mut input = String::new();
+                        io::stdin().read_to_string(&mut input)?;
+                        input
+                    }
+                };
+                let config = resolve_config(&aka_opts.config)?;
+                let content = fs::read_to_string(&config)?;
+                let (content, imported, skipped) = import_aliases(&content, &aka.spec, &input)?;
+                if !imported.is_empty() {
+                    fs::write(&config, content)?;
+                }
+                println!(
+                    "imported {} aliases into {:?}, skipped {} existing",
+                    imported.len(),
+                    config,
+                    skipped.len()
+                );
+                for name in skipped {
+                    println!("  skipped: {name}");
+                }
+            }
+            Command::Config(_) | Command::Edit | Command::Doctor | Command::History(_) | Command::Completions(_) => {
+                unreachable!("handled before the config is loaded")
+            }
+            Command::Preview(preview_opts) => {
+                let (space, enter) = preview(&aka, &preview_opts.cmdline)?;
+                for (label, result) in [("space", space), ("enter", enter)] {
+                    match result.is_empty() {
+                        true => println!("{label}: (unchanged)"),
+                        false => println!("{label}: {}", result.trim_end()),
                     }
                 }
             }
+            Command::Reverse(reverse_opts) => {
+                for name in reverse_aliases(&aka.spec, &reverse_opts.value) {
+                    println!("{name}");
+                }
+            }
+            Command::Stats(stats_opts) => {
+                let stats = compute_stats(&aka.spec, &resolve_config(&aka_opts.config)?)?;
+                if stats_opts.json {
+                    println!("{}", serde_json::to_string_pretty(&stats)?);
+                } else {
+                    print_stats(&stats);
+                }
+            }
+            Command::CompleteAliases(complete_opts) => {
+                for name in complete_aliases(&aka.spec, &complete_opts.prefix) {
+                    println!("{name}");
+                }
+            }
+            Command::CompleteValue(complete_opts) => {
+                if let Some(value) = complete_value(&aka.spec, &complete_opts.name) {
+                    println!("{value}");
+                }
+            }
+            Command::Search(search_opts) => {
+                for alias in search_aliases(&aka.spec, search_opts.query.as_deref().unwrap_or_default()) {
+                    print_alias(&alias, false, None);
+                }
+            }
         }
     }
     Ok(0)
@@ -307,9 +1971,32 @@ mod tests {
     use super::*;
     use eyre::{Error, Result};
     use pretty_assertions::assert_eq;
+    use std::cell::Cell;
     use std::collections::HashMap;
+    use std::rc::Rc;
     use tempfile::NamedTempFile;
 
+    /// Finds only the commands it is given, counting every lookup
+    #[derive(Debug, Default)]
+    struct MockRunner {
+        paths: HashMap<String, PathBuf>,
+        /// Output of each git invocation, keyed by its space-joined args; anything else fails
+        git: HashMap<String, String>,
+        calls: Rc<Cell<usize>>,
+    }
+
+    impl CommandRunner for MockRunner {
+        fn which(&self, command: &str) -> Option<PathBuf> {
+            self.calls.set(self.calls.get() + 1);
+            self.paths.get(command).cloned()
+        }
+
+        fn git(&self, args: &[&str]) -> Option<String> {
+            self.calls.set(self.calls.get() + 1);
+            self.git.get(&args.join(" ")).cloned()
+        }
+    }
+
     fn setup_aka(eol: bool, yaml: &str) -> Result<AKA> {
         let mut temp_file = NamedTempFile::new()?;
         writeln!(temp_file, "{}", yaml)?;
@@ -376,6 +2063,7 @@ mod tests {
                     value: "echo Hello World".to_string(),
                     space: true,
                     global: false,
+                    ..Default::default()
                 },
             );
             map
@@ -555,6 +2243,1878 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_leading_assignment_before_alias() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            cat: "bat -p"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        let result = aka.replace("FOO=bar cat file.txt")?;
+        let expect = "FOO=bar bat -p file.txt ";
+        assert_eq!(expect, result);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_leading_assignments_before_alias() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            cat: "bat -p"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        let result = aka.replace("FOO=bar BAZ_2=\"a b\" cat file.txt")?;
+        let expect = "FOO=bar BAZ_2=\"a b\" bat -p file.txt ";
+        assert_eq!(expect, result);
+        Ok(())
+    }
+
+    #[test]
+    fn test_leading_assignment_with_sudo() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            vim: "nvim"
+        "#;
+        let aka = setup_aka(true, yaml)?;
+        let result = aka.replace("FOO=bar vim file.txt !")?;
+        let expect = "sudo FOO=bar $(which nvim) file.txt ";
+        assert_eq!(expect, result);
+        Ok(())
+    }
+
+    fn list_opts(global: bool, patterns: &[&str]) -> ListOpts {
+        ListOpts {
+            global,
+            all: false,
+            show_disabled: false,
+            verbose: false,
+            tags: Vec::new(),
+            width: None,
+            json: true,
+            sort: SortKey::Name,
+            glob: false,
+            format: None,
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_list_json() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            cat: "bat -p"
+            '|c':
+                value: '| xclip -sel clip'
+                global: true
+            '^k-(.+)$':
+                value: 'kubectl $1'
+                regex: true
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        let opts = ListOpts {
+            all: true,
+            ..list_opts(false, &[])
+        };
+        let aliases = filter_aliases(&aka.spec, &opts);
+        let json: serde_json::Value = serde_json::from_str(&serde_json::to_string_pretty(&aliases)?)?;
+        let expect = serde_json::json!([
+            {"name": "^k-(.+)$", "value": "kubectl $1", "space": true, "global": false, "regex": true},
+            {"name": "cat", "value": "bat -p", "space": true, "global": false},
+            {"name": "|c", "value": "| xclip -sel clip", "space": true, "global": true},
+        ]);
+        assert_eq!(expect, json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_json_filters() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            cat: "bat -p"
+            cd: "z"
+            '|c':
+                value: '| xclip -sel clip'
+                global: true
+        "#;
+        let aka = setup_aka(false, yaml)?;
+
+        let globals = filter_aliases(&aka.spec, &list_opts(true, &[]));
+        let json: serde_json::Value = serde_json::from_str(&serde_json::to_string(&globals)?)?;
+        assert_eq!(json.as_array().map(Vec::len), Some(1));
+        assert_eq!(json[0]["name"], "|c");
+
+        let patterned = filter_aliases(&aka.spec, &list_opts(false, &["ca"]));
+        let json: serde_json::Value = serde_json::from_str(&serde_json::to_string(&patterned)?)?;
+        assert_eq!(json.as_array().map(Vec::len), Some(1));
+        assert_eq!(json[0]["name"], "cat");
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_glob_patterns() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            git-st: "git status"
+            git-co: "git checkout"
+            gitk: "gitk --all"
+            cc: "cargo check"
+            gc: "git commit"
+            abc: "echo abc"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        let names = |patterns: &[&str]| -> Vec<String> {
+            let opts = ListOpts {
+                glob: true,
+                ..list_opts(false, patterns)
+            };
+            filter_aliases(&aka.spec, &opts)
+                .into_iter()
+                .map(|alias| alias.name)
+                .collect()
+        };
+
+        assert_eq!(names(&["git-*"]), vec!["git-co", "git-st"]);
+        assert_eq!(names(&["?c"]), vec!["cc", "gc"]);
+        assert_eq!(names(&["git"]), Vec::<String>::new());
+        assert_eq!(filter_aliases(&aka.spec, &list_opts(false, &["git"])).len(), 3);
+        assert!(glob_match("*-*", "git-st"));
+        assert!(!glob_match("*-", "git-st"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_hides_pipe_aliases() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            cat: "bat -p"
+            '|c':
+                value: '| xclip -sel clip'
+                global: true
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        let names = |opts: &ListOpts| -> Vec<String> {
+            filter_aliases(&aka.spec, opts)
+                .into_iter()
+                .map(|alias| alias.name)
+                .collect()
+        };
+        assert_eq!(vec!["cat"], names(&list_opts(false, &[])));
+        let all = ListOpts {
+            all: true,
+            ..list_opts(false, &[])
+        };
+        assert_eq!(vec!["cat", "|c"], names(&all));
+        assert_eq!(vec!["|c"], names(&list_opts(true, &[])));
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_by_tag() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gs:
+                value: "git status"
+                tags: [git]
+            dps:
+                value: "docker ps"
+                tags: [docker]
+            kgp:
+                value: "kubectl get pods"
+                tags: [k8s, docker]
+            cat: "bat -p"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        let names = |tags: &[&str]| -> Vec<String> {
+            let opts = ListOpts {
+                tags: tags.iter().map(|t| t.to_string()).collect(),
+                ..list_opts(false, &[])
+            };
+            filter_aliases(&aka.spec, &opts)
+                .into_iter()
+                .map(|alias| alias.name)
+                .collect()
+        };
+        assert_eq!(vec!["gs"], names(&["git"]));
+        assert_eq!(vec!["dps", "kgp"], names(&["docker"]));
+        assert_eq!(vec!["gs", "kgp"], names(&["git", "k8s"]));
+        assert_eq!(vec!["cat", "dps", "gs", "kgp"], names(&[]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_expansions_stops_partway() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+            max_expansions: 3
+        aliases:
+            x: "y y y y"
+            y:
+                value: "z"
+                global: true
+        "#;
+        let aka = setup_aka(true, yaml)?;
+        assert_eq!(aka.replace("x")?, "y z z y ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_expansions_bounds_explosive_config() -> Result<()> {
+        // Each level doubles the line, so without the cap this would reach 2^12 words
+        let mut yaml = "defaults:\n  max_expansions: 100\naliases:\n".to_string();
+        for level in 0..12 {
+            yaml.push_str(&format!(
+                "  a{level}:\n    value: a{next} a{next}\n    global: true\n",
+                next = level + 1
+            ));
+        }
+        let aka = setup_aka(true, &yaml)?;
+        let words = aka.replace("a0")?.split_whitespace().count();
+        assert!(words > 1 && words <= 101, "{words} words");
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_insensitive_matching() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+            case_insensitive: true
+        aliases:
+            gc: "git commit"
+            GS: "git status"
+            ll: "ls -l"
+            LL: "ls -la"
+        "#;
+        let aka = setup_aka(true, yaml)?;
+        assert_eq!(aka.replace("GC -m x")?, "git commit -m x ");
+        assert_eq!(aka.replace("gs")?, "git status ");
+        assert_eq!(aka.replace("LL")?, "ls -la ");
+        assert_eq!(aka.replace("Ll")?, "ls -la ");
+        assert_eq!(aka.spec.aliases["GS"].name, "GS");
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_sensitive_by_default() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gc: "git commit"
+        "#;
+        let aka = setup_aka(true, yaml)?;
+        assert_eq!(aka.replace("GC")?, "");
+        assert_eq!(aka.replace("gc")?, "git commit ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_disabled_alias() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gs: "git status"
+            gco:
+                value: "git checkout"
+                enabled: false
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("", aka.replace("gco main")?);
+        assert_eq!("git status ", aka.replace("gs")?);
+
+        let names = |opts: &ListOpts| -> Vec<String> {
+            filter_aliases(&aka.spec, opts)
+                .into_iter()
+                .map(|alias| alias.name)
+                .collect()
+        };
+        assert_eq!(vec!["gs"], names(&list_opts(false, &[])));
+        let show = ListOpts {
+            show_disabled: true,
+            ..list_opts(false, &[])
+        };
+        assert_eq!(vec!["gco", "gs"], names(&show));
+        assert_eq!(vec!["gs"], complete_aliases(&aka.spec, "g"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_alias_with_template() {
+        let alias = Alias {
+            name: "gs".to_string(),
+            value: "git status {name}".to_string(),
+            global: true,
+            tags: vec!["git".to_string(), "vcs".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            "gs\tgit status {name}",
+            format_alias_with_template(&alias, "{name}\t{value}")
+        );
+        assert_eq!(
+            "alias gs (global=true, tags=git,vcs) {count}",
+            format_alias_with_template(&alias, "alias {name} (global={global}, tags={tags}) {count}")
+        );
+        assert_eq!("{name", format_alias_with_template(&alias, "{name"));
+        assert_eq!("plain text", format_alias_with_template(&alias, "plain text"));
+    }
+
+    #[test]
+    fn test_format_alias_width() {
+        let alias = Alias {
+            name: "gs".to_string(),
+            value: "git status --short".to_string(),
+            description: "show the working tree status".to_string(),
+            ..Default::default()
+        };
+        assert_eq!("gs: git status --short", format_alias(&alias, false, None));
+        assert_eq!("gs: git status --short", format_alias(&alias, false, Some(22)));
+        assert_eq!("gs: git status --sho…", format_alias(&alias, false, Some(21)));
+        assert_eq!("gs: git…", format_alias(&alias, false, Some(8)));
+        assert_eq!("gs: ", format_alias(&alias, false, Some(2)));
+        assert_eq!("# show the…\ngs: git st…", format_alias(&alias, true, Some(11)));
+
+        let multiline = Alias {
+            name: "deploy".to_string(),
+            value: "cd app\nmake release\n".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            "deploy: |\n  cd app\n  make release\n  ",
+            format_alias(&multiline, false, None)
+        );
+        assert_eq!(
+            "deploy: |\n  cd app\n  make …\n  ",
+            format_alias(&multiline, false, Some(8))
+        );
+    }
+
+    #[test]
+    fn test_list_sort_by_value() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            a: "zoxide"
+            b: "bat -p"
+            c: "exa"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+
+        let by_name: Vec<String> = filter_aliases(&aka.spec, &list_opts(false, &[]))
+            .into_iter()
+            .map(|alias| alias.name)
+            .collect();
+        assert_eq!(vec!["a", "b", "c"], by_name);
+
+        let opts = ListOpts {
+            sort: SortKey::Value,
+            ..list_opts(false, &[])
+        };
+        let by_value: Vec<String> = filter_aliases(&aka.spec, &opts)
+            .into_iter()
+            .map(|alias| alias.name)
+            .collect();
+        assert_eq!(vec!["b", "c", "a"], by_value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stdin_config_rejects_commands_needing_stdin_or_a_file() {
+        for args in [
+            vec!["query", "--batch"],
+            vec!["query", "-"],
+            vec!["__accept_line", "-"],
+            vec!["import"],
+            vec!["stats"],
+            vec!["edit"],
+            vec!["config", "--path"],
+            vec!["doctor"],
+        ] {
+            let opts = AkaOpts::parse_from(["aka", "--config", "-"].into_iter().chain(args.clone()));
+            let err = check_stdin_config(&opts.config, &opts.command).unwrap_err();
+            assert!(
+                err.to_string().starts_with("--config - cannot be combined with"),
+                "{args:?}"
+            );
+        }
+        for args in [vec!["query", "gs"], vec!["ls"], vec!["import"]] {
+            let opts = AkaOpts::parse_from(["aka"].into_iter().chain(args.clone()));
+            assert!(check_stdin_config(&opts.config, &opts.command).is_ok(), "{args:?}");
+        }
+        let opts = AkaOpts::parse_from(["aka", "--config", "-", "query", "gs"]);
+        assert!(check_stdin_config(&opts.config, &opts.command).is_ok());
+    }
+
+    #[test]
+    fn test_list_sort_defaults_to_name() {
+        let opts = ListOpts::parse_from(["ls"]);
+        assert_eq!(SortKey::Name, opts.sort);
+    }
+
+    #[test]
+    fn test_export_simple_alias() -> Result<()> {
+        let alias = Alias {
+            name: "cat".to_string(),
+            value: "bat -p".to_string(),
+            space: true,
+            global: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            Some("alias cat='bat -p'".to_string()),
+            export_alias(&alias, Shell::Bash)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_quotes_single_quotes() -> Result<()> {
+        let alias = Alias {
+            name: "hi".to_string(),
+            value: "echo 'hi'".to_string(),
+            space: true,
+            global: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(r"alias hi='echo '\''hi'\'''".to_string()),
+            export_alias(&alias, Shell::Zsh)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_variadic_alias_as_function() -> Result<()> {
+        let alias = Alias {
+            name: "gs".to_string(),
+            value: "git status $@".to_string(),
+            space: true,
+            global: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            Some("gs() {\n    git status $@\n}".to_string()),
+            export_alias(&alias, Shell::Zsh)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_wrapper_function_calls_command() -> Result<()> {
+        let alias = Alias {
+            name: "ls".to_string(),
+            value: "ls --color $@".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some("ls() {\n    command ls --color $@\n}".to_string()),
+            export_alias(&alias, Shell::Bash)?
+        );
+
+        let alias = Alias {
+            name: "l".to_string(),
+            value: "ls -l $@".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some("l() {\n    ls -l $@\n}".to_string()),
+            export_alias(&alias, Shell::Bash)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_unescapes_dollars() -> Result<()> {
+        let alias = Alias {
+            name: "price".to_string(),
+            value: r"echo \$5 \$HOME".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some("alias price='echo $5 $HOME'".to_string()),
+            export_alias(&alias, Shell::Bash)?
+        );
+
+        let alias = Alias {
+            name: "each".to_string(),
+            value: r"printf '\$%s\n' $@".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some("each() {\n    printf '\\$%s\\n' $@\n}".to_string()),
+            export_alias(&alias, Shell::Zsh)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_keeps_escaped_dollars_in_function_bodies() -> Result<()> {
+        let alias = Alias {
+            name: "say".to_string(),
+            value: r"echo \$1 $@".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some("say() {\n    echo \\$1 $@\n}".to_string()),
+            export_alias(&alias, Shell::Bash)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_skips_pipe_globals() -> Result<()> {
+        let alias = Alias {
+            name: "|c".to_string(),
+            value: "| xclip -sel clip".to_string(),
+            space: true,
+            global: true,
+            ..Default::default()
+        };
+        assert_eq!(None, export_alias(&alias, Shell::Zsh)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_skips_regex_and_conditional_aliases() -> Result<()> {
+        let regex = Alias {
+            name: "^deploy-(.+)$".to_string(),
+            value: "kubectl apply -f $1.yml".to_string(),
+            regex: true,
+            ..Default::default()
+        };
+        assert_eq!(None, export_alias(&regex, Shell::Zsh)?);
+        assert_eq!(None, export_alias(&regex, Shell::Bash)?);
+
+        let conditional = Alias {
+            name: "t".to_string(),
+            value: "cargo test".to_string(),
+            when: Some(cfg::alias::When {
+                cwd_contains: Some("Cargo.toml".to_string()),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(None, export_alias(&conditional, Shell::Zsh)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_shell_alias_quoting() {
+        assert_eq!(
+            Some(("ll".to_string(), "ls -la".to_string())),
+            parse_shell_alias("alias ll='ls -la'")
+        );
+        assert_eq!(
+            Some(("gs".to_string(), "git status".to_string())),
+            parse_shell_alias("gs=\"git status\"")
+        );
+        assert_eq!(
+            Some(("hi".to_string(), "echo 'hi' \"$USER\"".to_string())),
+            parse_shell_alias(r#"alias hi='echo '\''hi'\'' "$USER"'"#)
+        );
+        assert_eq!(
+            Some(("dq".to_string(), "echo \"a\" $HOME".to_string())),
+            parse_shell_alias(r#"alias dq="echo \"a\" \$HOME""#)
+        );
+        assert_eq!(None, parse_shell_alias("# not an alias"));
+    }
+
+    #[test]
+    fn test_import_aliases() -> Result<()> {
+        let yaml = "defaults:\n  version: 1\naliases:\n  cat: \"bat -p\"\n";
+        let aka = setup_aka(false, yaml)?;
+        let input = "alias cat='less'\nalias ll='ls -la'\nalias gs=\"git status\"\n";
+        let (content, imported, skipped) = import_aliases(yaml, &aka.spec, input)?;
+        assert_eq!(vec!["ll", "gs"], imported);
+        assert_eq!(vec!["cat"], skipped);
+
+        let aka = setup_aka(false, &content)?;
+        assert_eq!("bat -p", aka.spec.aliases["cat"].value);
+        assert_eq!("ls -la", aka.spec.aliases["ll"].value);
+        assert_eq!("git status", aka.spec.aliases["gs"].value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_aliases_keeps_env_refs_for_the_shell() -> Result<()> {
+        std::env::remove_var("EDITOR_NOPE_X");
+        let yaml = "defaults:\n  version: 1\naliases:\n  gs: git status\n";
+        let aka = setup_aka(true, yaml)?;
+        let input = "alias ed='$EDITOR_NOPE_X $@'\nalias h=\"cd ${HOME_NOPE_X}\"\n";
+        let (content, imported, _) = import_aliases(yaml, &aka.spec, input)?;
+        assert_eq!(vec!["ed", "h"], imported);
+
+        let aka = setup_aka(true, &content)?;
+        assert_eq!("git status ", aka.replace("gs")?);
+        assert_eq!("$EDITOR_NOPE_X notes.txt ", aka.replace("ed notes.txt")?);
+        assert_eq!("cd ${HOME_NOPE_X} ", aka.replace("h")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_aliases_without_aliases_section() -> Result<()> {
+        let yaml = "defaults:\n  version: 1\n";
+        let aka = setup_aka(false, yaml)?;
+        let (content, imported, _) = import_aliases(yaml, &aka.spec, "alias x='echo it'\\''s'\n")?;
+        assert_eq!(vec!["x"], imported);
+
+        let aka = setup_aka(false, &content)?;
+        assert_eq!("echo it's", aka.spec.aliases["x"].value);
+        Ok(())
+    }
+
+    fn search_names(aka: &AKA, query: &str) -> Vec<String> {
+        search_aliases(&aka.spec, query)
+            .into_iter()
+            .map(|alias| alias.name)
+            .collect()
+    }
+
+    #[test]
+    fn test_search_aliases() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gs: "git status"
+            gco: "git checkout"
+            cat: "bat -p"
+            dps: "docker ps"
+            gd:
+                value: "git diff"
+                enabled: false
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!(vec!["gco", "gs"], search_names(&aka, "G"));
+        assert_eq!(vec!["gco", "gs"], search_names(&aka, "git"));
+        assert_eq!(vec!["cat"], search_names(&aka, "bat"));
+        assert_eq!(vec!["dps"], search_names(&aka, "dp"));
+        assert_eq!(vec!["cat", "dps", "gco", "gs"], search_names(&aka, ""));
+        assert!(search_names(&aka, "nomatch").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_aliases_by_description() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gs: "git status"
+            gco:
+                value: "git checkout"
+                description: "Switch branches"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!(vec!["gco"], search_names(&aka, "branch"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_alias_inside_quotes_not_expanded_by_default() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gs: "git status"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        let result = aka.replace("watch \"gs -s\"")?;
+        assert_eq!("", result);
+        Ok(())
+    }
+
+    #[test]
+    fn test_alias_inside_quotes_expanded_with_flag() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gs: "git status"
+        "#;
+        let mut aka = setup_aka(false, yaml)?;
+        aka.expand_quoted = true;
+        let result = aka.replace("watch \"gs -s\" --label=\"gs\"")?;
+        let expect = "watch \"git status -s\" --label=\"git status\" ";
+        assert_eq!(expect, result);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_quoted_skips_escaped_and_single_quoted_quotes() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gs: "git status"
+        "#;
+        let mut aka = setup_aka(false, yaml)?;
+        aka.expand_quoted = true;
+        assert_eq!("", aka.replace(r#"echo a\"gs\"b '"gs"'"#)?);
+        let result = aka.replace(r#"echo '"gs"' "gs \"x\"""#)?;
+        assert_eq!(r#"echo '"gs"' "git status \"x\"" "#, result);
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex_alias() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            '^deploy-(.+)$':
+                value: "kubectl apply -f $1.yaml"
+                regex: true
+            '^k8s-(prod|dev)$':
+                value: "kubectl config use-context $1"
+                regex: true
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("kubectl apply -f web.yaml ", aka.replace("deploy-web")?);
+        assert_eq!("kubectl config use-context dev ", aka.replace("k8s-dev")?);
+        assert_eq!("", aka.replace("k8s-qa")?);
+        assert_eq!("", aka.replace("redeploy web")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_exact_alias_wins_over_regex_alias() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            deploy-web: "make deploy"
+            '^deploy-(.+)$':
+                value: "kubectl apply -f $1.yaml"
+                regex: true
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("make deploy ", aka.replace("deploy-web")?);
+        assert_eq!("kubectl apply -f api.yaml ", aka.replace("deploy-api")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookup_with_default() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            cat: "bat -p"
+        lookups:
+            region:
+                prod|apps: us-east-1
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("echo us-east-1 ", aka.replace("echo lookup:region[apps]")?);
+        assert_eq!("echo us-east-1 ", aka.replace("echo lookup:region[prod:us-west-2]")?);
+        assert_eq!("echo us-west-2 ", aka.replace("echo lookup:region[dev:us-west-2]")?);
+        assert_eq!("", aka.replace("echo lookup:region[dev]")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_complete_aliases_flat() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            git.commit: "git commit"
+            gs: "git status"
+            '|c':
+                value: '| xclip -sel clip'
+                global: true
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!(vec!["git.commit", "gs"], complete_aliases(&aka.spec, ""));
+        assert_eq!(vec!["git.commit"], complete_aliases(&aka.spec, "gi"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_complete_aliases_by_namespace() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+            namespace_sep: "."
+        aliases:
+            git.commit: "git commit"
+            git.push: "git push"
+            git.stash.pop: "git stash pop"
+            git.stash.list: "git stash list"
+            gs: "git status"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!(vec!["git.", "gs"], complete_aliases(&aka.spec, ""));
+        assert_eq!(vec!["git."], complete_aliases(&aka.spec, "gi"));
+        assert_eq!(
+            vec!["git.commit", "git.push", "git.stash."],
+            complete_aliases(&aka.spec, "git.")
+        );
+        assert_eq!(
+            vec!["git.stash.list", "git.stash.pop"],
+            complete_aliases(&aka.spec, "git.stash.")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_use_alias_variadic() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gs: "git status"
+            gc: "git commit -m $1"
+            e: "echo $@"
+            p:
+                value: "| less"
+                global: true
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        let alias = |name: &str| aka.spec.aliases[name].clone();
+        for name in ["gc", "e"] {
+            assert!(!aka.use_alias(&alias(name), 0, false), "{name} at 0 without eol");
+            assert!(aka.use_alias(&alias(name), 0, true), "{name} at 0 with eol");
+            assert!(!aka.use_alias(&alias(name), 1, false), "{name} at 1 without eol");
+            assert!(!aka.use_alias(&alias(name), 1, true), "{name} at 1 with eol");
+        }
+        assert!(aka.use_alias(&alias("gs"), 0, false));
+        assert!(!aka.use_alias(&alias("gs"), 1, true));
+        assert!(aka.use_alias(&alias("p"), 1, false));
+
+        assert_eq!("", aka.replace_with_mode("gc fix", false)?);
+        assert_eq!("git commit -m fix ", aka.replace_with_mode("gc fix", true)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_status() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gs: "git status"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        let expanded = aka.replace_status("gs")?;
+        assert_eq!(ReplaceStatus::Expanded("git status ".to_string()), expanded);
+        assert_eq!(0, expanded.exit_code());
+
+        let unchanged = aka.replace_status("ls -la")?;
+        assert_eq!(ReplaceStatus::Unchanged, unchanged);
+        assert_eq!(EXIT_QUERY_UNCHANGED, unchanged.exit_code());
+
+        let empty = aka.replace_status("   ")?;
+        assert_eq!(ReplaceStatus::Empty, empty);
+        assert_eq!(EXIT_QUERY_EMPTY, empty.exit_code());
+        Ok(())
+    }
+
+    #[test]
+    fn test_respect_comments() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+            respect_comments: true
+        aliases:
+            gc: "git commit -m $1"
+            gs: "git status"
+            wip: "work in progress"
+        "#;
+        let aka = setup_aka(true, yaml)?;
+        assert_eq!("git commit -m fix # wip gs ", aka.replace("gc fix # wip gs")?);
+        assert_eq!("git status  #   gs ", aka.replace("gs  #   gs")?);
+        assert_eq!("", aka.replace("# gs")?);
+        assert_eq!("", aka.replace("ls # gs")?);
+
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gc: "git commit -m $1"
+        "#;
+        let aka = setup_aka(true, yaml)?;
+        assert_eq!("", aka.replace("gc fix # wip")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sudo_wrap_disabled() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+            sudo_wrap: false
+        aliases:
+            v: "nvim"
+        "#;
+        let aka = setup_aka(true, yaml)?;
+        assert_eq!("sudo nvim file.txt ", aka.replace("v file.txt !")?);
+        Ok(())
+    }
+
+    fn mock_runner() -> MockRunner {
+        MockRunner {
+            paths: HashMap::from([
+                ("ls".to_string(), PathBuf::from("/usr/bin/ls")),
+                ("rg".to_string(), PathBuf::from("/home/me/.cargo/bin/rg")),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_git_placeholders() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            push: "git push origin $BRANCH"
+            pr: "gh pr create --head ${BRANCH} --title ${REPO}:$BRANCH"
+            lit: 'echo \$BRANCH'
+        "#;
+        let calls = Rc::new(Cell::new(0));
+        let runner = MockRunner {
+            git: HashMap::from([
+                ("rev-parse --abbrev-ref HEAD".to_string(), "feature/x".to_string()),
+                ("rev-parse --show-toplevel".to_string(), "/home/me/src/aka".to_string()),
+            ]),
+            calls: calls.clone(),
+            ..Default::default()
+        };
+        let mut temp_file = NamedTempFile::new()?;
+        writeln!(temp_file, "{}", yaml)?;
+        let aka = AKA::new(false, &Some(temp_file.path().to_path_buf()))?.with_runner(runner);
+
+        assert_eq!(aka.replace("push")?, "git push origin feature/x ");
+        assert_eq!(calls.get(), 1);
+        assert_eq!(
+            aka.replace("pr")?,
+            "gh pr create --head feature/x --title aka:feature/x "
+        );
+        assert_eq!(calls.get(), 3, "each placeholder runs git once per query");
+        query_batch(&aka, io::Cursor::new("push\npush\n"))?;
+        assert_eq!(calls.get(), 5, "a batch re-resolves on every line");
+        assert_eq!(aka.replace("lit")?, "echo $BRANCH ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_git_placeholders_outside_repo() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            push: "git push origin $BRANCH"
+        "#;
+        let aka = setup_aka(false, yaml)?.with_runner(MockRunner::default());
+        assert_eq!(aka.replace("push")?, "git push origin $BRANCH ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_line_expands_as_eol() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            g: "git $@"
+            ls: "ls -la"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        for cmdline in ["g push", "ls /root !", "ls"] {
+            assert_eq!(
+                ReplaceStatus::Expanded(aka.replace_with_mode(cmdline, true)?),
+                aka.accept_line(cmdline)?
+            );
+        }
+        assert_eq!(ReplaceStatus::Unchanged, aka.replace_status("g push")?);
+        assert_eq!(ReplaceStatus::Unchanged, aka.accept_line("echo hi")?);
+        assert_eq!(ReplaceStatus::Empty, aka.accept_line("  ")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preview() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            g: "git $@"
+            ls: "ls -la"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+
+        assert_eq!(("".to_string(), "git push ".to_string()), preview(&aka, "g push")?);
+
+        let (space, enter) = preview(&aka, "ls /root !")?;
+        assert_eq!("ls -la /root ! ", space);
+        assert!(enter.starts_with("sudo "), "{enter}");
+        assert_ne!(space, enter);
+
+        assert_eq!(("ls -la ".to_string(), "ls -la ".to_string()), preview(&aka, "ls")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shadow_warnings() -> Result<()> {
+        let spec: Spec = serde_yaml::from_str(
+            r#"
+            aliases:
+                ls: "ls -la"
+                rg: "grep -rn"
+                gs: "git status"
+                '|c': "| xclip"
+            "#,
+        )?;
+        assert_eq!(
+            vec![
+                "alias 'ls' wraps /usr/bin/ls; typing it never runs the bare command".to_string(),
+                "alias 'rg' shadows /home/me/.cargo/bin/rg with 'grep -rn'".to_string(),
+            ],
+            shadow_warnings(&spec, &mock_runner())
+        );
+        assert!(shadow_warnings(&spec, &MockRunner::default()).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_needs_sudo_wrapping() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        "#;
+        let runner = mock_runner();
+        let calls = Rc::clone(&runner.calls);
+        let aka = setup_aka(true, yaml)?.with_runner(runner);
+        assert!(aka.needs_sudo_wrapping("ls"));
+        assert!(aka.needs_sudo_wrapping("missing"));
+        assert_eq!("sudo $(which ls) -la ", aka.replace("ls -la !")?);
+        assert_eq!("sudo $(which rg) foo ", aka.replace("rg foo !")?);
+
+        // Without sudo_wrap_paths every command is wrapped, so nothing is looked up
+        assert_eq!(0, calls.get());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sudo_prefix() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gs: "git status"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("sudo git status ", aka.replace("sudo gs")?);
+        assert_eq!("sudo -- git status ", aka.replace("sudo -- gs")?);
+        assert_eq!("sudo -u bob -- git status ", aka.replace("sudo -u bob -- gs")?);
+        assert_eq!("sudo -u bob -E git status ", aka.replace("sudo -u bob -E gs")?);
+        assert_eq!("FOO=1 sudo BAR=2 git status ", aka.replace("FOO=1 sudo BAR=2 gs")?);
+        assert_eq!("", aka.replace("sudo -- -gs")?);
+        assert_eq!("", aka.replace("sudo -u gs")?);
+
+        let aka = setup_aka(true, yaml)?.with_runner(mock_runner());
+        assert_eq!("sudo -- $(which ls) -la ", aka.replace("sudo -- ls -la !")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_trigger_word_after_value_flag() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            cat: "bat -p"
+        "#;
+        let aka = setup_aka(true, yaml)?;
+        assert_eq!("bat -p ", aka.replace("sudo -u !cat")?);
+        assert_eq!("bat -p bob ", aka.replace("sudo -u bob -g !cat")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_privilege_command_doas() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+            privilege_command: doas
+        aliases:
+            gs: "git status"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("doas git status ", aka.replace("doas gs")?);
+        assert_eq!("doas -u bob git status ", aka.replace("doas -u bob gs")?);
+        assert_eq!("", aka.replace("sudo gs")?);
+
+        let aka = setup_aka(true, yaml)?.with_runner(mock_runner());
+        assert_eq!("doas $(which ls) -la ", aka.replace("ls -la !")?);
+        assert_eq!("doas $(which rg) foo ", aka.replace("rg foo !")?);
+        assert_eq!("doas -u bob $(which ls) ", aka.replace("doas -u bob ls !")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sudo_trigger() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+            sudo_trigger: ";;"
+        aliases:
+            v: "nvim"
+        "#;
+        let aka = setup_aka(true, yaml)?.with_runner(mock_runner());
+        assert_eq!("sudo $(which ls) -la ", aka.replace("ls -la ;;")?);
+        assert_eq!("sudo $(which ls) -la ", aka.replace("ls -la;;")?);
+        assert_eq!("sudo $(which nvim) file.txt ", aka.replace("v file.txt ;;")?);
+        assert_eq!("", aka.replace("ls -la !")?);
+        assert_eq!("sudo $(which echo) ';;' ", aka.replace("echo ';;' ;;")?);
+        assert_eq!(vec!["ls", ";;"], AKA::split_respecting_quotes("ls;;  ", ";;").0);
+
+        let aka = setup_aka(true, "defaults:\n  sudo_trigger: ''")?.with_runner(mock_runner());
+        assert_eq!("", aka.replace("ls -la !")?);
+
+        let aka = setup_aka(true, "defaults:\n  version: 1")?.with_runner(mock_runner());
+        assert_eq!("sudo $(which ls) -la ", aka.replace("ls -la !")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sudo_wrap_paths() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+            sudo_wrap_paths: ["/home/me/.cargo/bin"]
+        "#;
+        let runner = mock_runner();
+        let calls = Rc::clone(&runner.calls);
+        let aka = setup_aka(true, yaml)?.with_runner(runner);
+        assert!(aka.needs_sudo_wrapping("rg"));
+        assert!(!aka.needs_sudo_wrapping("ls"));
+        assert!(!aka.needs_sudo_wrapping("missing"));
+        assert_eq!("sudo missing ", aka.replace("missing !")?);
+        assert_eq!("sudo $(which rg) foo ", aka.replace("rg foo !")?);
+
+        // Each command name is looked up once per instance
+        assert_eq!(3, calls.get());
+
+        let dirs = vec!["/usr/local/bin".to_string()];
+        assert!(in_wrap_paths(Path::new("/usr/local/bin/rg"), &dirs));
+        assert!(!in_wrap_paths(Path::new("/usr/bin/ls"), &dirs));
+        assert!(!in_wrap_paths(Path::new("/usr/local/bin/sub/rg"), &dirs));
+        Ok(())
+    }
+
+    #[test]
+    fn test_system_runner() -> Result<()> {
+        let file = NamedTempFile::new()?;
+        let path = file.path().display().to_string();
+        assert_eq!(Some(file.path().to_path_buf()), SystemRunner.which(&path));
+        assert_eq!(None, SystemRunner.which("/nonexistent/definitely-not-a-command"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_cmdline_len() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+            max_cmdline_len: 10
+        aliases:
+            gs: "git status"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("git status -s ", aka.replace("gs -s     ")?);
+        assert_eq!("", aka.replace("gs -s      ")?);
+
+        let aka = setup_aka(false, "aliases:\n  gs: git status")?;
+        let long = format!("gs {}", "x".repeat(60 * 1024));
+        assert!(aka.replace(&long)?.starts_with("git status x"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_explicit_alias_references() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            build: "cargo build"
+            test: "cargo test"
+            ci: "alias:build && alias:test"
+            broken: "alias:missing && ls"
+            loop: "alias:loop"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("cargo build && cargo test ", aka.replace("ci")?);
+        assert_eq!("alias:missing && ls ", aka.replace("broken")?);
+        assert_eq!("alias:loop ", aka.replace("loop")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_stats() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gs: "git status"
+            gc: "git commit -m $1"
+            e: "echo $@"
+            '|c':
+                value: '| xclip -sel clip'
+                global: true
+            '^g(\d+)$':
+                value: 'git log -$1'
+                regex: true
+            old:
+                value: "ls"
+                enabled: false
+        lookups:
+            region:
+                prod: us-east-1
+        "#;
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, "{yaml}")?;
+        let config = file.path().to_path_buf();
+        let aka = AKA::new(false, &Some(config.clone()))?;
+        let stats = compute_stats(&aka.spec, &config)?;
+        assert_eq!(
+            Stats {
+                config: config.clone(),
+                config_bytes: fs::metadata(&config)?.len(),
+                config_hash: format!("{:016x}", hash_file(&config)?),
+                aliases: 6,
+                global: 1,
+                variadic: 3,
+                regex: 1,
+                disabled: 1,
+                lookups: 1,
+            },
+            stats
+        );
+        let json: serde_json::Value = serde_json::from_str(&serde_json::to_string(&stats)?)?;
+        assert_eq!(json["variadic"], 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion_scripts() {
+        let bash = completion_script(CompletionShell::Bash);
+        assert!(bash.contains("aka __complete_aliases"));
+        assert!(bash.contains("complete -o nospace -F _aka_complete_aliases aka"));
+
+        let fish = completion_script(CompletionShell::Fish);
+        assert!(fish.contains("aka __complete_aliases"));
+        assert!(fish.contains("complete -c aka"));
+    }
+
+    #[test]
+    fn test_global_only() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            ls:
+                value: "eza"
+                global_only: true
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        let alias = aka.spec.aliases["ls"].clone();
+        assert!(!aka.use_alias(&alias, 0, false));
+        assert!(aka.use_alias(&alias, 1, false));
+        assert_eq!("", aka.replace("ls -la")?);
+        assert_eq!("watch eza ", aka.replace("watch ls")?);
+
+        let anywhere = AKA { anywhere: true, ..aka };
+        assert_eq!("", anywhere.replace("ls -la")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_cmdline() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gc: "git commit -m"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        let piped = read_cmdline("-", io::Cursor::new("gc 'it''s \"done\"'\r\nignored\n"))?;
+        assert_eq!("gc 'it''s \"done\"'", piped);
+        assert_eq!(aka.replace(r#"gc 'it''s "done"'"#)?, aka.replace(&piped)?);
+        assert_eq!("gc fix", read_cmdline("gc fix", io::Cursor::new("unused"))?);
+        assert_eq!("", read_cmdline("-", io::Cursor::new(""))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_batch() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gs: "git status"
+            gc: "git commit -m"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        let results = query_batch(&aka, io::Cursor::new("gs\nls -la\n\ngc fix\n"))?;
+        assert_eq!(vec!["git status ", "", "", "git commit -m fix "], results);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_aliases() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gs: "git status"
+            gss: "git status --short"
+            st: "git status"
+            gco: "git checkout"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!(vec!["gs", "st", "gss"], reverse_aliases(&aka.spec, "git status"));
+        assert_eq!(vec!["gss"], reverse_aliases(&aka.spec, "git status --s"));
+        assert!(reverse_aliases(&aka.spec, "docker ps").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_when_cwd_contains() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            build:
+                value: "cargo build"
+                when:
+                    cwd_contains: Cargo.toml
+        "#;
+        let rust = tempfile::tempdir()?;
+        fs::write(rust.path().join("Cargo.toml"), "")?;
+        let other = tempfile::tempdir()?;
+
+        let aka = setup_aka(false, yaml)?;
+        let aka = AKA {
+            cwd: Some(rust.path().to_path_buf()),
+            ..aka
+        };
+        assert_eq!("cargo build --release ", aka.replace("build --release")?);
+
+        let aka = AKA {
+            cwd: Some(other.path().to_path_buf()),
+            ..aka
+        };
+        assert_eq!("", aka.replace("build --release")?);
+
+        let aka = AKA { cwd: None, ..aka };
+        assert_eq!("", aka.replace("build")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_doctor_checks() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = dir.path().join("aka.yml");
+        assert!(!check_config_found(&Some(config.clone())).passed);
+
+        fs::write(&config, "aliases:\n  gs: git status\n")?;
+        assert!(check_config_found(&Some(config.clone())).passed);
+        assert_eq!(
+            Check {
+                name: "config parses",
+                passed: true,
+                detail: "1 aliases, 0 warnings".to_string(),
+            },
+            check_config_parses(&config)
+        );
+
+        fs::write(&config, "aliases:\n  gs: [unterminated\n")?;
+        let parses = check_config_parses(&config);
+        assert!(!parses.passed);
+        assert!(parses.detail.contains("aka edit"));
+
+        assert!(check_log_writable(false, &dir.path().join("missing/aka.log")).passed);
+        assert!(!check_log_writable(true, &dir.path().join("missing/aka.log")).passed);
+        assert!(check_log_writable(true, &dir.path().join("aka.log")).passed);
+
+        assert!(!check_on_path(Some(dir.path().into())).passed);
+        fs::write(dir.path().join("aka"), "")?;
+        assert!(check_on_path(Some(dir.path().into())).passed);
+        assert!(!check_on_path(None).passed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_complete_value() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gs: "git status"
+            '^g(\d+)$':
+                value: 'git log -$1'
+                regex: true
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!(Some("git status".to_string()), complete_value(&aka.spec, "gs"));
+        assert_eq!(None, complete_value(&aka.spec, "missing"));
+        assert_eq!(None, complete_value(&aka.spec, "^g(\\d+)$"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_chained_lookups() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        lookups:
+            region:
+                prod: us-east-1
+                alias: "lookup:region[prod]"
+            zone:
+                us-east-1: use1-az1
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("echo use1-az1 ", aka.replace("echo lookup:zone[lookup:region[prod]]")?);
+        assert_eq!("echo us-east-1 ", aka.replace("echo lookup:region[alias]")?);
+        // Only an arg that is itself a lookup token resolves, not one embedded in a longer word
+        assert_eq!("", aka.replace("echo --zone=lookup:zone[us-east-1]")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_self_referential_lookup_hits_depth_cap() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        lookups:
+            loop:
+                a: "x-lookup:loop[a]"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        let result = aka.replace("echo lookup:loop[a]")?;
+        let expect = format!("echo {}lookup:loop[a] ", "x-".repeat(MAX_LOOKUP_DEPTH));
+        assert_eq!(expect, result);
+        Ok(())
+    }
+
+    #[test]
+    fn test_anywhere_expands_non_global_alias_mid_line() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            cat: "bat -p"
+        "#;
+        let mut aka = setup_aka(false, yaml)?;
+        assert_eq!("", aka.replace("xargs cat file.txt")?);
+
+        aka.anywhere = true;
+        assert_eq!("xargs bat -p file.txt ", aka.replace("xargs cat file.txt")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_alias_two_cycle_is_broken() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            a:
+                value: "x b"
+                global: true
+            b:
+                value: "y a"
+                global: true
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("echo x y a ", aka.replace("echo a")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_alias_three_cycle_is_broken() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            a:
+                value: "x b"
+                global: true
+            b:
+                value: "y c"
+                global: true
+            c:
+                value: "z a"
+                global: true
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("echo x y z a ", aka.replace("echo a")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_alias_finite_chain_fully_expands() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            a:
+                value: "x b"
+                global: true
+            b:
+                value: "y c"
+                global: true
+            c:
+                value: "literal"
+                global: true
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("echo x y literal ", aka.replace("echo a")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_trailing_space_follows_last_token() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gc:
+                value: 'git commit -m"'
+                space: false
+            cat: "bat -p"
+            '|c':
+                value: '| xclip -sel clip'
+                global: true
+            '|n':
+                value: '| nl -ba'
+                global: true
+                space: false
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("git commit -m\"", aka.replace("gc")?);
+        assert_eq!("git commit -m\" fix ", aka.replace("gc fix")?);
+        assert_eq!("git commit -m\" | xclip -sel clip ", aka.replace("gc |c")?);
+        assert_eq!("bat -p file.txt | nl -ba", aka.replace("cat file.txt |n")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserves_multiple_spaces_with_alias_match() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            ls: "exa"
+            '|c':
+                value: '| xclip -sel clip'
+                global: true
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("exa    -la ", aka.replace("ls    -la")?);
+        assert_eq!(
+            "exa -la  \"a  b\"   | xclip -sel clip ",
+            aka.replace("ls -la  \"a  b\"   |c")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserves_multiple_spaces_without_alias_match() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            ls: "exa"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        // No match means no output, so the shell keeps the original line untouched
+        assert_eq!("", aka.replace("cat    file.txt")?);
+        let (args, seps) = AKA::split_respecting_quotes("cat    file.txt  ", "!");
+        assert_eq!(vec!["cat", "file.txt"], args);
+        assert_eq!(vec!["", "    "], seps);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_respecting_single_quotes() {
+        let (args, _) = AKA::split_respecting_quotes("echo 'hello world' done", "!");
+        assert_eq!(vec!["echo", "'hello world'", "done"], args);
+    }
+
+    #[test]
+    fn test_split_respecting_mixed_quotes() {
+        let (args, _) = AKA::split_respecting_quotes(r#"echo "it's here" 'say "hi" now' end"#, "!");
+        assert_eq!(vec!["echo", "\"it's here\"", "'say \"hi\" now'", "end"], args);
+    }
+
+    #[test]
+    fn test_split_respecting_escaped_quotes() {
+        let (args, _) = AKA::split_respecting_quotes(r#"echo \"a b\" "c \" d" 'e\' f"#, "!");
+        assert_eq!(vec!["echo", r#"\"a"#, r#"b\""#, r#""c \" d""#, r"'e\'", "f"], args);
+    }
+
+    #[test]
+    fn test_single_quoted_args_with_alias() -> Result<()> {
+        let yaml = r#"
+        defaults:
+            version: 1
+        aliases:
+            gc: "git commit -m"
+        "#;
+        let aka = setup_aka(false, yaml)?;
+        assert_eq!("git commit -m 'fix the bug' ", aka.replace("gc 'fix the bug'")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_respecting_quotes_multibyte() {
+        let (args, _) = AKA::split_respecting_quotes("echo héllo wörld!", "!");
+        assert_eq!(vec!["echo", "héllo", "wörld", "!"], args);
+    }
+
+    #[test]
+    fn test_log_format_selection() {
+        assert_eq!(LogFormat::Text, log_format(None));
+        assert_eq!(LogFormat::Text, log_format(Some("text")));
+        assert_eq!(LogFormat::Text, log_format(Some("bogus")));
+        assert_eq!(LogFormat::Json, log_format(Some("json")));
+        assert_eq!(LogFormat::Json, log_format(Some("JSON")));
+    }
+
+    #[test]
+    fn test_format_log_line_text() {
+        assert_eq!(
+            "'a' -> 'b'",
+            format_log_line(LogFormat::Text, 1.5, LogLevel::Info, "'a' -> 'b'")
+        );
+        assert_eq!(
+            "warn: careful",
+            format_log_line(LogFormat::Text, 1.5, LogLevel::Warn, "careful")
+        );
+    }
+
+    #[test]
+    fn test_format_log_line_json() -> Result<()> {
+        let line = format_log_line(LogFormat::Json, 1.5, LogLevel::Warn, "say \"hi\"");
+        let json: serde_json::Value = serde_json::from_str(&line)?;
+        let expect = serde_json::json!({"ts": 1.5, "level": "warn", "target": "aka", "msg": "say \"hi\""});
+        assert_eq!(expect, json);
+        assert!(!line.contains('\n'));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotate_log_if_needed_rotates_oversized_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("aka.log");
+        fs::write(&path, "x".repeat(100))?;
+        fs::write(dir.path().join("aka.log.1"), "older")?;
+        fs::write(dir.path().join("aka.log.2"), "oldest")?;
+
+        assert!(rotate_log_if_needed(&path, 50, 2)?);
+        assert!(!path.exists());
+        assert_eq!("x".repeat(100), fs::read_to_string(dir.path().join("aka.log.1"))?);
+        assert_eq!("older", fs::read_to_string(dir.path().join("aka.log.2"))?);
+        assert!(!dir.path().join("aka.log.3").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_batch_records_history() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("aka.history");
+        let yaml = "defaults:\n  version: 1\naliases:\n  gs: git status\n";
+        let aka = AKA {
+            history: Some(path.clone()),
+            ..setup_aka(false, yaml)?
+        };
+        query_batch(&aka, io::Cursor::new("gs\nls -l\n"))?;
+        let entries: Vec<(String, String)> = read_history(&path)?
+            .into_iter()
+            .map(|entry| (entry.input, entry.output))
+            .collect();
+        assert_eq!(
+            vec![
+                ("gs".to_string(), "git status ".to_string()),
+                ("ls -l".to_string(), String::new())
+            ],
+            entries
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_history_trims_to_cap() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("aka.history");
+        assert!(read_history(&path)?.is_empty());
+
+        let entry = |n: usize| HistoryEntry {
+            ts: n as f64,
+            input: format!("gs {n}"),
+            output: format!("git status {n} "),
+        };
+        append_history(&path, entry(1), 3)?;
+        assert_eq!(vec![entry(1)], read_history(&path)?);
+
+        for n in 2..=5 {
+            append_history(&path, entry(n), 3)?;
+        }
+        assert_eq!(vec![entry(3), entry(4), entry(5)], read_history(&path)?);
+        assert_eq!(3, fs::read_to_string(&path)?.lines().count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotate_log_if_needed_leaves_small_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("aka.log");
+        fs::write(&path, "small")?;
+
+        assert!(!rotate_log_if_needed(&path, 50, 2)?);
+        assert_eq!("small", fs::read_to_string(&path)?);
+        assert!(!dir.path().join("aka.log.1").exists());
+        assert!(!rotate_log_if_needed(&dir.path().join("missing.log"), 50, 2)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_spec_from_stdin() -> Result<()> {
+        let stdin = "aliases:\n  gs: git status\n".as_bytes();
+        let spec = load_spec(&Some(PathBuf::from("-")), stdin)?;
+        let aka = AKA::from_spec(true, spec)?.with_runner(MockRunner::default());
+        assert_eq!(aka.replace("gs -s")?, "git status -s ");
+
+        assert!(load_spec(&Some(PathBuf::from("-")), "aliases: [".as_bytes()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_config_with_override() -> Result<()> {
+        let file = NamedTempFile::new()?;
+        let config = Some(file.path().to_path_buf());
+        assert_eq!(file.path(), resolve_config(&config)?);
+        assert!(resolve_config(&Some(PathBuf::from("/path/to/nonexistent/aka.yml"))).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_config_precedence() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let flag = dir.path().join("flag.yml");
+        let env = dir.path().join("env.yml");
+        let searched = dir.path().join("aka.yml");
+        for file in [&flag, &env, &searched] {
+            fs::write(file, "aliases: {}")?;
+        }
+        let configs = vec![searched.clone()];
+
+        assert_eq!(
+            flag,
+            resolve_config_from(&Some(flag.clone()), Some(env.clone()), &configs)?
+        );
+        assert_eq!(env, resolve_config_from(&None, Some(env.clone()), &configs)?);
+        assert_eq!(searched, resolve_config_from(&None, None, &configs)?);
+
+        let missing = dir.path().join("missing.yml");
+        let err = resolve_config_from(&None, Some(missing), &configs)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("AKA_CONFIG"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_divine_config_from_search_order() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let first = dir.path().join("aka.yml");
+        let second = dir.path().join(".aka.yml");
+        let configs = vec![first.clone(), second.clone()];
+        assert!(divine_config_from(&configs).is_err());
+
+        fs::write(&second, "aliases: {}")?;
+        assert_eq!(second, divine_config_from(&configs)?);
+
+        fs::write(&first, "aliases: {}")?;
+        assert_eq!(first, divine_config_from(&configs)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_config() -> Result<()> {
+        let mut good = NamedTempFile::new()?;
+        writeln!(
+            good,
+            "aliases:\n  gs: git status\n  gap: echo $1 $3\nlookups:\n  region:\n    prod: us-east-1"
+        )?;
+        let (spec, warnings) = validate_config(good.path())?;
+        assert_eq!(2, spec.aliases.len());
+        assert_eq!(1, spec.lookups.len());
+        assert_eq!(vec!["alias 'gap' skips positional parameter $2"], warnings);
+
+        let mut bad = NamedTempFile::new()?;
+        writeln!(bad, "aliases:\n  gs: [unterminated")?;
+        assert!(validate_config(bad.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_config_load() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(
+            file,
+            "aliases:\n  gs: git status\nlookups:\n  region:\n    prod|apps: us-east-1"
+        )?;
+        let timings = time_config_load(file.path(), 3)?;
+        assert_eq!(3, timings.runs);
+        assert!(timings.min > Duration::ZERO);
+        assert!(timings.min <= timings.avg);
+        assert!(timings.avg < Duration::from_secs(5));
+
+        let mut bad = NamedTempFile::new()?;
+        writeln!(bad, "aliases:\n  gs: [unterminated")?;
+        assert!(time_config_load(bad.path(), 3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_edit() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, "aliases:\n  gs: git status")?;
+        let before = hash_file(file.path())?;
+        assert_eq!(EditOutcome::Unchanged, check_edit(file.path(), before)?);
+
+        fs::write(file.path(), "aliases:\n  gs: git status -s\n")?;
+        assert_eq!(EditOutcome::Changed, check_edit(file.path(), before)?);
+
+        fs::write(file.path(), "aliases:\n  gs: [unterminated\n")?;
+        assert!(matches!(check_edit(file.path(), before)?, EditOutcome::Invalid(_)));
+        Ok(())
+    }
+
     #[test]
     fn test_no_substitution() -> Result<()> {
         let yaml = r#"